@@ -0,0 +1,247 @@
+use crate::mutex::Mutex;
+use crate::result::Error;
+use crate::result::Result;
+use alloc::vec::Vec;
+use core::mem::size_of;
+use core::ptr::read_unaligned;
+use core::slice;
+
+#[repr(C, packed)]
+struct Rsdp {
+    signature: [u8; 8],
+    checksum: u8,
+    oem_id: [u8; 6],
+    revision: u8,
+    rsdt_address: u32,
+    // 以下はACPI 2.0以降
+    length: u32,
+    xsdt_address: u64,
+    extended_checksum: u8,
+    _reserved: [u8; 3],
+}
+const RSDP_V1_SIZE: usize = 20;
+
+// すべてのシステム記述表の先頭にあるヘッダ
+#[repr(C, packed)]
+pub struct SdtHeader {
+    signature: [u8; 4],
+    length: u32,
+    revision: u8,
+    checksum: u8,
+    oem_id: [u8; 6],
+    oem_table_id: [u8; 8],
+    oem_revision: u32,
+    creator_id: u32,
+    creator_revision: u32,
+}
+const _: () = assert!(size_of::<SdtHeader>() == 36);
+
+impl SdtHeader {
+    pub fn signature(&self) -> [u8; 4] {
+        self.signature
+    }
+    pub fn length(&self) -> usize {
+        self.length as usize
+    }
+    fn addr(&self) -> usize {
+        self as *const Self as usize
+    }
+    // ヘッダに続く本体
+    pub fn body(&self) -> &[u8] {
+        unsafe {
+            slice::from_raw_parts(
+                (self.addr() + size_of::<Self>()) as *const u8,
+                self.length() - size_of::<Self>(),
+            )
+        }
+    }
+    fn is_valid(&self) -> bool {
+        self.length() >= size_of::<Self>() && checksum(self.addr(), self.length()) == 0
+    }
+}
+
+fn checksum(addr: usize, len: usize) -> u8 {
+    let bytes = unsafe { slice::from_raw_parts(addr as *const u8, len) };
+    bytes.iter().fold(0u8, |sum, b| sum.wrapping_add(*b))
+}
+
+// XSDT (ACPI 2.0以降) かRSDTのどちらかを根にして表を探す
+struct RootTable {
+    header: &'static SdtHeader,
+    entry_size: usize,
+}
+
+unsafe impl Send for RootTable {}
+
+impl RootTable {
+    fn tables(&self) -> impl Iterator<Item = &'static SdtHeader> + '_ {
+        let body = self.header.body();
+        body.chunks_exact(self.entry_size).map(|entry| {
+            let addr = if self.entry_size == size_of::<u64>() {
+                unsafe { read_unaligned(entry.as_ptr() as *const u64) as usize }
+            } else {
+                unsafe { read_unaligned(entry.as_ptr() as *const u32) as usize }
+            };
+            unsafe { &*(addr as *const SdtHeader) }
+        })
+    }
+}
+
+static ROOT_TABLE: Mutex<Option<RootTable>> = Mutex::new(None);
+
+// RSDPを検証して、XSDTかRSDTを覚えておく
+pub fn init(rsdp_addr: usize) -> Result<()> {
+    let rsdp = unsafe { &*(rsdp_addr as *const Rsdp) };
+    if rsdp.signature != *b"RSD PTR " || checksum(rsdp_addr, RSDP_V1_SIZE) != 0 {
+        return Err(Error::InvalidAcpiTable(*b"RSDP"));
+    }
+    let root = if rsdp.revision >= 2 {
+        if checksum(rsdp_addr, rsdp.length as usize) != 0 {
+            return Err(Error::InvalidAcpiTable(*b"RSDP"));
+        }
+        RootTable {
+            header: unsafe { &*(rsdp.xsdt_address as usize as *const SdtHeader) },
+            entry_size: size_of::<u64>(),
+        }
+    } else {
+        RootTable {
+            header: unsafe { &*(rsdp.rsdt_address as usize as *const SdtHeader) },
+            entry_size: size_of::<u32>(),
+        }
+    };
+    if !root.header.is_valid() {
+        return Err(Error::InvalidAcpiTable(root.header.signature()));
+    }
+    *ROOT_TABLE.lock() = Some(root);
+    Ok(())
+}
+
+// 署名が一致し、チェックサムも正しい表を返す
+pub fn find_table(signature: &[u8; 4]) -> Option<&'static SdtHeader> {
+    let root = ROOT_TABLE.lock();
+    let root = root.as_ref()?;
+    let table = root
+        .tables()
+        .find(|table| table.signature == *signature && table.is_valid());
+    table
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IoApicInfo {
+    pub id: u8,
+    pub addr: usize,
+    pub gsi_base: u32,
+}
+
+// ISAのIRQが、別のGSIや極性、トリガモードで配線されていることを表す
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InterruptOverride {
+    pub irq: u8,
+    pub gsi: u32,
+    pub active_low: bool,
+    pub level_triggered: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Madt {
+    pub local_apic_addr: usize,
+    pub has_8259: bool,
+    pub local_apic_ids: Vec<u8>,
+    pub io_apics: Vec<IoApicInfo>,
+    pub overrides: Vec<InterruptOverride>,
+}
+
+const MADT_PCAT_COMPAT: u32 = 1 << 0;
+const MADT_LOCAL_APIC: u8 = 0;
+const MADT_IO_APIC: u8 = 1;
+const MADT_INTERRUPT_OVERRIDE: u8 = 2;
+const MADT_LOCAL_APIC_ADDRESS_OVERRIDE: u8 = 5;
+const MADT_LOCAL_APIC_ENABLED: u32 = 1 << 0;
+// MPS INTIフラグ。0はバスの既定 (ISAではアクティブハイ、エッジトリガ)
+const MPS_ACTIVE_LOW: u16 = 0b11;
+const MPS_LEVEL_TRIGGERED: u16 = 0b11 << 2;
+
+fn read_u16(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([bytes[offset], bytes[offset + 1]])
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+fn read_u64(bytes: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(bytes[offset..offset + 8].try_into().unwrap())
+}
+
+// MADT ("APIC") から、割り込みコントローラの構成を読み取る
+pub fn madt() -> Result<Madt> {
+    let body = find_table(b"APIC").ok_or(Error::DeviceNotFound)?.body();
+    if body.len() < 8 {
+        return Err(Error::InvalidAcpiTable(*b"APIC"));
+    }
+    let mut madt = Madt {
+        local_apic_addr: read_u32(body, 0) as usize,
+        has_8259: read_u32(body, 4) & MADT_PCAT_COMPAT != 0,
+        local_apic_ids: Vec::new(),
+        io_apics: Vec::new(),
+        overrides: Vec::new(),
+    };
+    let mut offset = 8;
+    while offset + 2 <= body.len() {
+        let entry_type = body[offset];
+        let len = body[offset + 1] as usize;
+        if len < 2 || offset + len > body.len() {
+            return Err(Error::InvalidAcpiTable(*b"APIC"));
+        }
+        let entry = &body[offset..offset + len];
+        match entry_type {
+            MADT_LOCAL_APIC if len >= 8 => {
+                if read_u32(entry, 4) & MADT_LOCAL_APIC_ENABLED != 0 {
+                    madt.local_apic_ids.push(entry[3]);
+                }
+            }
+            MADT_IO_APIC if len >= 12 => madt.io_apics.push(IoApicInfo {
+                id: entry[2],
+                addr: read_u32(entry, 4) as usize,
+                gsi_base: read_u32(entry, 8),
+            }),
+            MADT_INTERRUPT_OVERRIDE if len >= 10 => {
+                let flags = read_u16(entry, 8);
+                madt.overrides.push(InterruptOverride {
+                    irq: entry[3],
+                    gsi: read_u32(entry, 4),
+                    active_low: flags & MPS_ACTIVE_LOW == MPS_ACTIVE_LOW,
+                    level_triggered: flags & MPS_LEVEL_TRIGGERED == MPS_LEVEL_TRIGGERED,
+                });
+            }
+            MADT_LOCAL_APIC_ADDRESS_OVERRIDE if len >= 12 => {
+                madt.local_apic_addr = read_u64(entry, 4) as usize;
+            }
+            _ => {}
+        }
+        offset += len;
+    }
+    Ok(madt)
+}
+
+#[cfg(test)]
+mod test {
+    use super::find_table;
+    use super::madt;
+
+    #[test_case]
+    fn madt_describes_io_apic() {
+        let table = find_table(b"APIC").expect("MADT not found");
+        assert_eq!(&table.signature(), b"APIC");
+        let madt = madt().unwrap();
+        assert_ne!(madt.local_apic_addr, 0);
+        assert!(!madt.local_apic_ids.is_empty());
+        assert!(!madt.io_apics.is_empty());
+        assert!(madt.overrides.iter().all(|e| (e.irq as usize) < 16));
+    }
+
+    #[test_case]
+    fn missing_table_is_not_found() {
+        assert!(find_table(b"NONE").is_none());
+    }
+}
@@ -1,45 +1,60 @@
-extern crate alloc;
-
+use crate::result::Error;
+#[cfg(feature = "buddy_allocator")]
+use crate::buddy::BuddyAllocator;
+#[cfg(feature = "buddy_allocator")]
+use crate::buddy::BUDDY_ALLOCATOR;
+use crate::mutex::Mutex;
 use crate::result::Result;
-use crate::uefi::MemoryDescriptor;
-use crate::uefi::EfiMemoryType;
-use crate::uefi::MemoryMapHolder;
+use crate::slab::SlabAllocator;
+use crate::x86::serial::SerialPort;
+use crate::x86::serial::COM1;
 use alloc::alloc::GlobalAlloc;
 use alloc::alloc::Layout;
 use alloc::boxed::Box;
 use core::borrow::BorrowMut;
-use core::cell::RefCell;
 use core::cmp::max;
+use core::cmp::min;
 use core::fmt;
+use core::fmt::Write;
 use core::mem::size_of;
 use core::ops::DerefMut;
 use core::panic;
+use core::ptr::copy_nonoverlapping;
 use core::ptr::null_mut;
+use core::sync::atomic::AtomicUsize;
+use core::sync::atomic::Ordering;
 
 pub fn round_up_to_nearest_pow2(v: usize) -> Result<usize> {
     1usize
         .checked_shl(usize::BITS - v.wrapping_sub(1).leading_zeros())
-        .ok_or("Out of range")
+        .ok_or(Error::OutOfRange)
 }
 
 struct Header {
     next_header: Option<Box<Header>>,
     size: usize,
     is_allocated: bool,
-    _reserved: usize,
+    // リストで直前にあるヘッダのアドレス。先頭なら0。解放時に直前のブロックと結合するのに使う
+    prev_addr: usize,
 }
 const HEADER_SIZE: usize = size_of::<Header>();
+// 切り離した末尾が、ヘッダとその後ろの最小のデータ領域を持てる大きさ
+const MIN_SPLIT_SIZE: usize = HEADER_SIZE * 2;
+// heap_debugフィーチャで、割り当ての前後に置くレッドゾーン
+const RED_ZONE_SIZE: usize = 16;
+const CANARY_BYTE: u8 = 0xca;
+const POISON_BYTE: u8 = 0xde;
 pub const LAYOUT_PAGE_4K: Layout =
     unsafe { Layout::from_size_align_unchecked(4096, 4096) };
 impl Header {
-    fn can_provide(&self, size: usize, align: usize) -> bool {
-        self.size >= size + HEADER_SIZE * 2 + align
-    }
     fn is_allocated(&self) -> bool {
         self.is_allocated
     }
+    fn addr(&self) -> usize {
+        self as *const Header as usize
+    }
     fn end_addr(&self) -> usize {
-        self as *const Header as usize + self.size
+        self.addr() + self.size
     }
     unsafe fn new_from_addr(addr: usize) -> Box<Header> {
         let header = addr as *mut Header;
@@ -47,10 +62,18 @@ impl Header {
             next_header: None,
             size: 0,
             is_allocated: false,
-            _reserved: 0,
+            prev_addr: 0,
         });
         Box::from_raw(addr as *mut Header)
     }
+    // 次のヘッダを付け替え、その直前のヘッダとして自分を記録する
+    fn set_next(&mut self, next: Option<Box<Header>>) {
+        self.next_header = next;
+        let addr = self.addr();
+        if let Some(next) = self.next_header.as_deref_mut() {
+            next.prev_addr = addr;
+        }
+    }
     unsafe fn from_allocated_region(addr: *mut u8) -> Box<Header> {
         let header = addr.sub(HEADER_SIZE) as *mut Header;
         Box::from_raw(header)
@@ -58,33 +81,92 @@ impl Header {
     fn provide(&mut self, size: usize, align: usize) -> Option<*mut u8> {
         let size = max(round_up_to_nearest_pow2(size).ok()?, HEADER_SIZE);
         let align = max(align, HEADER_SIZE);
-        if self.is_allocated() || !self.can_provide(size, align) {
+        if self.is_allocated() {
+            return None;
+        }
+        let self_addr = self as *const Header as usize;
+        let allocated_addr = self.end_addr().checked_sub(size)? & !(align - 1);
+        let header_addr = allocated_addr.checked_sub(HEADER_SIZE)?;
+        if header_addr < self_addr {
+            None
+        } else if header_addr == self_addr {
+            // ブロック全体をそのまま使う (末尾の余りも含める)
+            self.is_allocated = true;
+            Some(allocated_addr as *mut u8)
+        } else if header_addr - self_addr < HEADER_SIZE {
             None
         } else {
             let mut size_used = 0;
-            let allocated_addr = (self.end_addr() - size) & !(align - 1);
             let mut header_for_allocated =
-                unsafe { Self::new_from_addr(allocated_addr - HEADER_SIZE) };
+                unsafe { Self::new_from_addr(header_addr) };
             header_for_allocated.is_allocated = true;
             header_for_allocated.size = size + HEADER_SIZE;
             size_used += header_for_allocated.size;
-            header_for_allocated.next_header = self.next_header.take();
+            header_for_allocated.set_next(self.next_header.take());
             if header_for_allocated.end_addr() != self.end_addr() {
                 let mut header_for_padding =
                     unsafe { Self::new_from_addr(header_for_allocated.end_addr()) };
-                    header_for_allocated.is_allocated = false;
-                    header_for_padding.size =
-                        self.end_addr() - header_for_allocated.end_addr();
-                        size_used += header_for_padding.size;
-                        header_for_padding.next_header =
-                            header_for_allocated.next_header.take();
-                        header_for_allocated.next_header = Some(header_for_padding);
+                header_for_padding.is_allocated = false;
+                header_for_padding.size =
+                    self.end_addr() - header_for_allocated.end_addr();
+                size_used += header_for_padding.size;
+                header_for_padding.set_next(header_for_allocated.next_header.take());
+                header_for_allocated.set_next(Some(header_for_padding));
             }
             self.size -= size_used;
-            self.next_header = Some(header_for_allocated);
+            self.set_next(Some(header_for_allocated));
             Some(allocated_addr as *mut u8)
         }
     }
+    // 割り当て済みブロックのデータ部に入るバイト数
+    fn capacity(&self) -> usize {
+        self.size - HEADER_SIZE
+    }
+    // データ部がsizeバイト入る大きさまでブロックを縮め、
+    // 余った末尾は空きブロックとして切り離す
+    fn shrink_to_fit(&mut self, size: usize) {
+        let block_size = HEADER_SIZE + max(size, HEADER_SIZE).next_multiple_of(HEADER_SIZE);
+        if self.size < block_size + MIN_SPLIT_SIZE {
+            return;
+        }
+        let mut tail = unsafe { Self::new_from_addr(self.addr() + block_size) };
+        tail.size = self.size - block_size;
+        tail.set_next(self.next_header.take());
+        tail.merge_with_following_free_blocks();
+        self.size = block_size;
+        self.set_next(Some(tail));
+    }
+    // 直後に隣接する空きブロックを取り込んで、データ部をsizeバイトまで広げる
+    fn grow_in_place(&mut self, size: usize) -> bool {
+        match self.next_header.as_deref() {
+            Some(next)
+                if !next.is_allocated()
+                    && next.addr() == self.end_addr()
+                    && self.capacity() + next.size >= size => {}
+            _ => return false,
+        }
+        let mut next = self.next_header.take().unwrap();
+        self.size += next.size;
+        self.next_header = next.next_header.take();
+        Box::leak(next);
+        self.shrink_to_fit(size);
+        true
+    }
+    // 直後の空きブロックが隣接していれば、すべて取り込む
+    fn merge_with_following_free_blocks(&mut self) {
+        if self.is_allocated() {
+            return;
+        }
+        while let Some(next) = self.next_header.as_deref() {
+            if next.is_allocated() || self.end_addr() != next as *const Header as usize {
+                break;
+            }
+            let mut next = self.next_header.take().unwrap();
+            self.size += next.size;
+            self.set_next(next.next_header.take());
+            Box::leak(next);
+        }
+    }
 }
 impl Drop for Header {
     fn drop(&mut self) {
@@ -104,37 +186,156 @@ impl fmt::Debug for Header {
     }
 }
 
+#[alloc_error_handler]
+fn alloc_error_handler(layout: Layout) -> ! {
+    panic!("Allocation failed: {layout:?}");
+}
+
+// サイズはすべてヘッダを含むブロック単位のバイト数
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct HeapStats {
+    pub total_bytes: usize,
+    pub free_bytes: usize,
+    pub used_bytes: usize,
+    pub largest_free_block: usize,
+    pub block_count: usize,
+    pub free_block_count: usize,
+    pub allocation_count: usize,
+    pub deallocation_count: usize,
+}
+
+impl HeapStats {
+    fn add_block(&mut self, header: &Header) {
+        self.block_count += 1;
+        if header.is_allocated() {
+            self.used_bytes += header.size;
+        } else {
+            self.free_block_count += 1;
+            self.free_bytes += header.size;
+            self.largest_free_block = max(self.largest_free_block, header.size);
+        }
+    }
+}
+
+// ヒープのバックエンドが共通して持つ操作
+pub trait HeapAllocator: GlobalAlloc {
+    // 使えなかった領域は、黙って捨てずにエラーで知らせる
+    fn add_free_region(&self, start_addr: usize, size: usize) -> Result<()>;
+    fn stats(&self) -> HeapStats;
+    fn validate(&self) -> Result<HeapStats>;
+}
+
 pub struct FirstFitAllocator {
-    first_header: RefCell<Option<Box<Header>>>,
+    first_header: Mutex<Option<Box<Header>>>,
+    total_bytes: AtomicUsize,
+    allocation_count: AtomicUsize,
+    deallocation_count: AtomicUsize,
+    debug_error_count: AtomicUsize,
 }
 
+pub static ALLOCATOR: FirstFitAllocator = FirstFitAllocator::new();
+
+// 小さなオブジェクトはスラブから、それ以外はバックエンドから確保する。
+// バックエンドはbuddy_allocatorフィーチャで切り替える
+#[cfg(not(feature = "buddy_allocator"))]
 #[global_allocator]
-pub static ALLOCATOR: FirstFitAllocator = FirstFitAllocator {
-    first_header: RefCell::new(None),
-};
+pub static KERNEL_HEAP: SlabAllocator<FirstFitAllocator> = SlabAllocator::new(&ALLOCATOR);
 
-unsafe impl Sync for FirstFitAllocator {}
+#[cfg(feature = "buddy_allocator")]
+#[global_allocator]
+pub static KERNEL_HEAP: SlabAllocator<BuddyAllocator> = SlabAllocator::new(&BUDDY_ALLOCATOR);
 
 unsafe impl GlobalAlloc for FirstFitAllocator {
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        if cfg!(feature = "heap_debug") {
+            return self.alloc_with_red_zones(layout);
+        }
         self.alloc_with_options(layout)
     }
     unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
-        let mut region = Header::from_allocated_region(ptr);
-        region.is_allocated = false;
-        Box::leak(region);
+        if cfg!(feature = "heap_debug") {
+            return self.dealloc_with_red_zones(ptr, layout);
+        }
+        let mut first_header = self.first_header.lock();
+        self.free_block(&mut first_header, ptr);
+    }
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        // ブロック全体ではなく、要求されたサイズだけをゼロにする
+        let ptr = self.alloc(layout);
+        if !ptr.is_null() {
+            ptr.write_bytes(0, layout.size());
+        }
+        ptr
+    }
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        // レッドゾーンがあるとその場では伸縮できないので、常に移す
+        if !cfg!(feature = "heap_debug") {
+            let _first_header = self.first_header.lock();
+            let header = Box::leak(Header::from_allocated_region(ptr));
+            let resized = if new_size <= header.capacity() {
+                header.shrink_to_fit(new_size);
+                true
+            } else {
+                header.grow_in_place(new_size)
+            };
+            if resized {
+                return ptr;
+            }
+        }
+        // その場で広げられなければ、新しいブロックに移す
+        let new_layout = Layout::from_size_align_unchecked(new_size, layout.align());
+        let new_ptr = self.alloc(new_layout);
+        if !new_ptr.is_null() {
+            copy_nonoverlapping(ptr, new_ptr, min(layout.size(), new_size));
+            self.dealloc(ptr, layout);
+        }
+        new_ptr
     }
 }
 
+// レッドゾーンを含めて確保するレイアウトと、その先頭から要求された領域までのオフセット。
+// レッドゾーンを足すと大きすぎるレイアウトになるならNone
+fn red_zone_layout(layout: Layout) -> Option<(Layout, usize)> {
+    let offset = max(RED_ZONE_SIZE, layout.align());
+    let size = offset
+        .checked_add(layout.size())?
+        .checked_add(RED_ZONE_SIZE)?;
+    let outer = Layout::from_size_align(size, layout.align()).ok()?;
+    Some((outer, offset))
+}
+
 impl FirstFitAllocator {
+    pub const fn new() -> Self {
+        Self {
+            first_header: Mutex::new(None),
+            total_bytes: AtomicUsize::new(0),
+            allocation_count: AtomicUsize::new(0),
+            deallocation_count: AtomicUsize::new(0),
+            debug_error_count: AtomicUsize::new(0),
+        }
+    }
+
+    // ブロックを数える前の統計。ロックを取った状態で呼ぶ
+    fn counters(&self) -> HeapStats {
+        HeapStats {
+            total_bytes: self.total_bytes.load(Ordering::Relaxed),
+            allocation_count: self.allocation_count.load(Ordering::Relaxed),
+            deallocation_count: self.deallocation_count.load(Ordering::Relaxed),
+            ..Default::default()
+        }
+    }
+
     pub fn alloc_with_options(&self, layout: Layout) -> *mut u8 {
-        let mut header = self.first_header.borrow_mut();
+        let mut header = self.first_header.lock();
         let mut header = header.deref_mut();
         loop {
             match header {
                 Some(e) => {
                     match e.provide(layout.size(), layout.align()) {
-                        Some(p) => break p,
+                        Some(p) => {
+                            self.allocation_count.fetch_add(1, Ordering::Relaxed);
+                            break p;
+                        }
                         None => {
                             header = e.next_header.borrow_mut();
                             continue;
@@ -148,40 +349,489 @@ impl FirstFitAllocator {
         }
     }
 
-    pub fn init_with_mmap(&self, memory_map: &MemoryMapHolder) {
-        for e in memory_map.iter() {
-            if e.memory_type() != EfiMemoryType::CONVENTIONAL_MEMORY {
-                continue;
-            }
-            self.add_free_from_descriptor(e);
+    // 解放したブロックを、リストで隣り合う空きブロックとだけ結合する。
+    // ヒープ全体は辿らないが、リストを書き換えるのでロックを取った状態で呼ぶ
+    unsafe fn free_block(&self, _first_header: &mut Option<Box<Header>>, ptr: *mut u8) {
+        let region = Box::leak(Header::from_allocated_region(ptr));
+        region.is_allocated = false;
+        region.merge_with_following_free_blocks();
+        if region.prev_addr != 0 {
+            let prev = &mut *(region.prev_addr as *mut Header);
+            prev.merge_with_following_free_blocks();
         }
+        self.deallocation_count.fetch_add(1, Ordering::Relaxed);
     }
 
-    fn add_free_from_descriptor(
-        &self,
-        descriptor: &MemoryDescriptor,
-    ) 
-    {
-        let mut start_addr = descriptor.physical_start() as usize;
-        let mut size = descriptor.number_of_pages() as usize * 4096;
-        if start_addr == 0 {
-            start_addr+= 4096;
-            size = size.saturating_sub(4096);
+    // 要求された領域の前後に、カナリアで埋めたレッドゾーンを付けて確保する
+    pub(crate) fn alloc_with_red_zones(&self, layout: Layout) -> *mut u8 {
+        let Some((outer, offset)) = red_zone_layout(layout) else {
+            return null_mut();
+        };
+        let block = self.alloc_with_options(outer);
+        if block.is_null() {
+            return block;
         }
-        if size <= 4096 {
+        unsafe {
+            block.write_bytes(CANARY_BYTE, offset);
+            block
+                .add(offset + layout.size())
+                .write_bytes(CANARY_BYTE, RED_ZONE_SIZE);
+            block.add(offset)
+        }
+    }
+
+    // 二重解放とレッドゾーンの破壊を検出してから、データ部を毒で埋めて解放する。
+    // ptrはalloc_with_red_zonesに同じlayoutを渡して得たものでなければならない
+    pub(crate) unsafe fn dealloc_with_red_zones(&self, ptr: *mut u8, layout: Layout) {
+        // 確保できたのだから、同じlayoutのレッドゾーンは必ず収まる
+        let Some((_, offset)) = red_zone_layout(layout) else {
+            return;
+        };
+        let block = ptr.sub(offset);
+        let mut first_header = self.first_header.lock();
+        let header = &*(block.sub(HEADER_SIZE) as *const Header);
+        if !header.is_allocated() {
+            // 解放済みのブロックに触ると壊れるので、報告するだけにとどめる
+            self.report_debug_error("double free", ptr, layout);
             return;
         }
+        let is_intact = |start: usize, len: usize| {
+            (start..start + len).all(|i| unsafe { *block.add(i) } == CANARY_BYTE)
+        };
+        if !is_intact(0, offset) {
+            self.report_debug_error("buffer underflow", ptr, layout);
+        }
+        if !is_intact(offset + layout.size(), RED_ZONE_SIZE) {
+            self.report_debug_error("buffer overflow", ptr, layout);
+        }
+        block.write_bytes(POISON_BYTE, header.capacity());
+        self.free_block(&mut first_header, block);
+    }
+
+    fn report_debug_error(&self, kind: &str, ptr: *mut u8, layout: Layout) {
+        self.debug_error_count.fetch_add(1, Ordering::Relaxed);
+        let mut serial = SerialPort::new(COM1);
+        writeln!(serial, "HEAP: {kind} detected at {ptr:p} ({layout:?})").unwrap();
+    }
+
+    // heap_debugフィーチャで検出した誤りの数
+    pub fn debug_error_count(&self) -> usize {
+        self.debug_error_count.load(Ordering::Relaxed)
+    }
+
+    // 隣り合った空きブロックを結合し、大きな要求にも再利用できるようにする
+    fn coalesce(first_header: &mut Option<Box<Header>>) {
+        let mut header = first_header.as_deref_mut();
+        while let Some(e) = header {
+            e.merge_with_following_free_blocks();
+            header = e.next_header.as_deref_mut();
+        }
+    }
+}
+
+impl HeapAllocator for FirstFitAllocator {
+    fn add_free_region(&self, start_addr: usize, size: usize) -> Result<()> {
+        if size <= 4096 {
+            return Err(Error::InvalidParameter);
+        }
         let mut header = unsafe {
             Header::new_from_addr(start_addr)
         };
         header.next_header = None;
         header.is_allocated = false;
         header.size = size;
-        let mut first_header = self.first_header.borrow_mut();
-        let prev_last = first_header.replace(header);
-        drop(first_header);
-        let mut header = self.first_header.borrow_mut();
-        header.as_mut().unwrap().next_header = prev_last;
- 
-    }
-}
\ No newline at end of file
+        // アドレス順に挿入しておくと、隣接する記述子同士も結合できる
+        let mut first_header = self.first_header.lock();
+        let mut cursor = first_header.deref_mut();
+        let mut prev_addr = 0;
+        while let Some(e) = cursor.as_ref().filter(|e| e.addr() < start_addr) {
+            prev_addr = e.addr();
+            cursor = &mut cursor.as_mut().unwrap().next_header;
+        }
+        header.set_next(cursor.take());
+        header.prev_addr = prev_addr;
+        *cursor = Some(header);
+        Self::coalesce(&mut first_header);
+        self.total_bytes.fetch_add(size, Ordering::Relaxed);
+        Ok(())
+    }
+
+    fn stats(&self) -> HeapStats {
+        let first_header = self.first_header.lock();
+        let mut stats = self.counters();
+        let mut header = first_header.as_deref();
+        while let Some(e) = header {
+            stats.add_block(e);
+            header = e.next_header.as_deref();
+        }
+        stats
+    }
+
+    // ヒープを先頭から辿り、ヘッダの不変条件を検証する。
+    // 壊れていれば、最初に見つかったおかしなヘッダのアドレスを返す。
+    // 統計も同じロックの中で数えるので、並行する確保や解放で食い違うことはない
+    fn validate(&self) -> Result<HeapStats> {
+        let first_header = self.first_header.lock();
+        let mut stats = self.counters();
+        let mut header = first_header.as_deref();
+        let mut prev_addr = 0;
+        let mut prev_end_addr = 0;
+        let mut prev_is_free_and_adjacent = false;
+        let mut last_addr = 0;
+        while let Some(e) = header {
+            let addr = e.addr();
+            if addr % HEADER_SIZE != 0
+                || e.size < HEADER_SIZE
+                || e.size % HEADER_SIZE != 0
+                || addr < prev_end_addr
+                || e.end_addr() < addr
+                || e.prev_addr != prev_addr
+            {
+                return Err(Error::HeapCorruption(addr));
+            }
+            // 隣接する空きブロックは必ず結合されているはず
+            let is_adjacent = addr == prev_end_addr;
+            if is_adjacent && prev_is_free_and_adjacent && !e.is_allocated() {
+                return Err(Error::HeapCorruption(addr));
+            }
+            prev_is_free_and_adjacent = !e.is_allocated();
+            prev_end_addr = e.end_addr();
+            stats.add_block(e);
+            // ブロックの合計が、追加された領域の合計を超えたところで壊れている
+            if stats.used_bytes + stats.free_bytes > stats.total_bytes {
+                return Err(Error::HeapCorruption(addr));
+            }
+            last_addr = addr;
+            prev_addr = addr;
+            header = e.next_header.as_deref();
+        }
+        // 合計が足りなければ、最後のブロックより後ろが失われている
+        if stats.used_bytes + stats.free_bytes != stats.total_bytes {
+            return Err(Error::HeapCorruption(last_addr));
+        }
+        Ok(stats)
+    }
+}
+
+impl Default for FirstFitAllocator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod test_suite {
+    use super::HeapAllocator;
+    use crate::frame_allocator::FRAME_ALLOCATOR;
+    use crate::frame_allocator::FRAME_SIZE;
+    use alloc::alloc::Layout;
+    use alloc::vec::Vec;
+    use core::sync::atomic::AtomicBool;
+    use core::sync::atomic::Ordering;
+
+    const TEST_HEAP_SIZE: usize = 16 * 1024 * 1024;
+
+    // グローバルなヒープとは別に、テスト用のインスタンスを物理フレームから初期化する。
+    // こうしておくと、どちらのバックエンドが選ばれていても両方をテストできる
+    pub fn init_test_heap<A: HeapAllocator>(
+        allocator: &'static A,
+        initialized: &AtomicBool,
+    ) -> &'static A {
+        if !initialized.swap(true, Ordering::SeqCst) {
+            let addr = FRAME_ALLOCATOR
+                .alloc_contiguous(TEST_HEAP_SIZE / FRAME_SIZE, FRAME_SIZE)
+                .expect("Out of frames");
+            allocator
+                .add_free_region(addr, TEST_HEAP_SIZE)
+                .expect("Failed to add the test heap");
+        }
+        allocator
+    }
+
+    pub fn freed_block_is_reused<A: HeapAllocator>(allocator: &A) {
+        let layout = Layout::from_size_align(100, 8).unwrap();
+        let free_size = allocator.stats().free_bytes;
+        let p1 = unsafe { allocator.alloc(layout) };
+        assert!(!p1.is_null());
+        assert!(allocator.stats().free_bytes < free_size);
+        unsafe { allocator.dealloc(p1, layout) };
+        assert_eq!(allocator.stats().free_bytes, free_size);
+        let p2 = unsafe { allocator.alloc(layout) };
+        assert_eq!(p1, p2);
+        unsafe { allocator.dealloc(p2, layout) };
+    }
+
+    pub fn free_size_is_restored_after_mixed_alloc_and_free<A: HeapAllocator>(allocator: &A) {
+        const NUM_OBJECTS: usize = 1000;
+        const NUM_ROUNDS: usize = 4;
+        let mut allocated = Vec::with_capacity(NUM_OBJECTS);
+        let free_size = allocator.stats().free_bytes;
+        // 決定的な疑似乱数 (線形合同法)
+        let mut seed: u64 = 0x2545_f491_4f6c_dd1d;
+        let mut rand = || {
+            seed = seed.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+            (seed >> 33) as usize
+        };
+        for _ in 0..NUM_ROUNDS {
+            for _ in 0..NUM_OBJECTS {
+                let size = 1 + rand() % 4096;
+                let align = 1 << (rand() % 13);
+                let layout = Layout::from_size_align(size, align).unwrap();
+                let p = unsafe { allocator.alloc(layout) };
+                assert!(!p.is_null());
+                assert_eq!(p as usize % align, 0);
+                unsafe { p.write_bytes(0xa5, size) };
+                allocated.push((p, layout));
+            }
+            // 確保した順とは異なる順で解放する
+            while !allocated.is_empty() {
+                let i = rand() % allocated.len();
+                let (p, layout) = allocated.swap_remove(i);
+                unsafe { allocator.dealloc(p, layout) };
+            }
+            assert_eq!(allocator.stats().free_bytes, free_size);
+            assert!(allocator.validate().is_ok());
+        }
+    }
+
+    pub fn stats_track_allocations<A: HeapAllocator>(allocator: &A) {
+        let layout = Layout::from_size_align(100, 8).unwrap();
+        let before = allocator.validate().expect("Heap is corrupted");
+        let p = unsafe { allocator.alloc(layout) };
+        let during = allocator.stats();
+        assert_eq!(during.allocation_count, before.allocation_count + 1);
+        assert!(during.used_bytes > before.used_bytes);
+        assert_eq!(during.used_bytes + during.free_bytes, during.total_bytes);
+        assert!(during.largest_free_block <= during.free_bytes);
+        unsafe { allocator.dealloc(p, layout) };
+        let after = allocator.validate().expect("Heap is corrupted");
+        assert_eq!(after.deallocation_count, before.deallocation_count + 1);
+        assert_eq!(after.used_bytes, before.used_bytes);
+        assert_eq!(after.free_bytes, before.free_bytes);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::test_suite;
+    use super::FirstFitAllocator;
+    use super::HeapAllocator;
+    use super::POISON_BYTE;
+    use alloc::alloc::GlobalAlloc;
+    use alloc::alloc::Layout;
+    use alloc::boxed::Box;
+    use alloc::vec::Vec;
+    use core::sync::atomic::AtomicBool;
+
+    static TEST_FIRST_FIT: FirstFitAllocator = FirstFitAllocator::new();
+    static TEST_FIRST_FIT_INITIALIZED: AtomicBool = AtomicBool::new(false);
+
+    fn test_first_fit() -> &'static FirstFitAllocator {
+        test_suite::init_test_heap(&TEST_FIRST_FIT, &TEST_FIRST_FIT_INITIALIZED)
+    }
+
+    #[test_case]
+    fn malloc_box() {
+        let b = Box::new(0x1234_5678_u64);
+        assert_eq!(*b, 0x1234_5678);
+    }
+
+    #[test_case]
+    fn malloc_vec() {
+        let mut v = Vec::new();
+        for i in 0..1000u64 {
+            v.push(i);
+        }
+        assert_eq!(v.iter().sum::<u64>(), 999 * 1000 / 2);
+    }
+
+    #[test_case]
+    fn freed_block_is_reused() {
+        test_suite::freed_block_is_reused(test_first_fit());
+    }
+
+    // 解放したブロックは、直前と直後の空きブロックの両方と結合される
+    #[test_case]
+    fn dealloc_merges_with_both_neighbours() {
+        let allocator = test_first_fit();
+        let layout = Layout::from_size_align(256, 8).unwrap();
+        let before = allocator.validate().unwrap();
+        let p: Vec<*mut u8> = (0..3).map(|_| unsafe { allocator.alloc(layout) }).collect();
+        unsafe {
+            allocator.dealloc(p[0], layout);
+            allocator.dealloc(p[2], layout);
+            allocator.dealloc(p[1], layout);
+        }
+        let after = allocator.validate().unwrap();
+        assert_eq!(after.free_block_count, before.free_block_count);
+        assert_eq!(after.free_bytes, before.free_bytes);
+    }
+
+    #[test_case]
+    fn free_size_is_restored_after_mixed_alloc_and_free() {
+        test_suite::free_size_is_restored_after_mixed_alloc_and_free(test_first_fit());
+    }
+
+    #[test_case]
+    fn stats_track_allocations() {
+        test_suite::stats_track_allocations(test_first_fit());
+    }
+
+    // heap_debugフィーチャでは、レッドゾーンがあるので常に移す
+    #[cfg(not(feature = "heap_debug"))]
+    #[test_case]
+    fn realloc_grows_in_place_into_following_free_block() {
+        let allocator = test_first_fit();
+        let layout = Layout::from_size_align(256, 8).unwrap();
+        // first-fitは空きブロックの末尾から切り出すので、p2の直後にp1が来る
+        let p1 = unsafe { allocator.alloc(layout) };
+        let p2 = unsafe { allocator.alloc(layout) };
+        unsafe {
+            p2.write_bytes(0x5a, layout.size());
+            allocator.dealloc(p1, layout);
+        }
+        let p3 = unsafe { allocator.realloc(p2, layout, 1000) };
+        assert_eq!(p3, p2);
+        assert_eq!(unsafe { *p3.add(layout.size() - 1) }, 0x5a);
+        assert!(allocator.validate().is_ok());
+        unsafe { allocator.dealloc(p3, Layout::from_size_align(1000, 8).unwrap()) };
+    }
+
+    #[cfg(not(feature = "heap_debug"))]
+    #[test_case]
+    fn realloc_shrinks_in_place() {
+        let allocator = test_first_fit();
+        let layout = Layout::from_size_align(4096, 8).unwrap();
+        let free_size = allocator.stats().free_bytes;
+        let p1 = unsafe { allocator.alloc(layout) };
+        let used = free_size - allocator.stats().free_bytes;
+        let p2 = unsafe { allocator.realloc(p1, layout, 100) };
+        assert_eq!(p2, p1);
+        assert!(free_size - allocator.stats().free_bytes < used);
+        assert!(allocator.validate().is_ok());
+        unsafe { allocator.dealloc(p2, Layout::from_size_align(100, 8).unwrap()) };
+        assert_eq!(allocator.stats().free_bytes, free_size);
+    }
+
+    #[cfg(feature = "heap_debug")]
+    #[test_case]
+    fn realloc_moves_block_with_red_zones() {
+        let allocator = test_first_fit();
+        let layout = Layout::from_size_align(256, 8).unwrap();
+        let grown = Layout::from_size_align(1000, 8).unwrap();
+        let shrunk = Layout::from_size_align(100, 8).unwrap();
+        let errors = allocator.debug_error_count();
+        let p1 = unsafe { allocator.alloc(layout) };
+        unsafe { p1.write_bytes(0x5a, layout.size()) };
+        // 伸ばしても縮めても別のブロックに移り、中身は引き継がれる
+        let p2 = unsafe { allocator.realloc(p1, layout, grown.size()) };
+        assert_ne!(p2, p1);
+        assert!((0..layout.size()).all(|i| unsafe { *p2.add(i) } == 0x5a));
+        let p3 = unsafe { allocator.realloc(p2, grown, shrunk.size()) };
+        assert_ne!(p3, p2);
+        assert!((0..shrunk.size()).all(|i| unsafe { *p3.add(i) } == 0x5a));
+        unsafe { allocator.dealloc(p3, shrunk) };
+        assert_eq!(allocator.debug_error_count(), errors);
+        assert!(allocator.validate().is_ok());
+    }
+
+    #[test_case]
+    fn realloc_moves_and_preserves_contents() {
+        let allocator = test_first_fit();
+        let layout = Layout::from_size_align(256, 8).unwrap();
+        let free_size = allocator.stats().free_bytes;
+        // p1が残っているので、p2はその場では広げられない
+        let p1 = unsafe { allocator.alloc(layout) };
+        let p2 = unsafe { allocator.alloc(layout) };
+        for i in 0..layout.size() {
+            unsafe { p2.add(i).write(i as u8) };
+        }
+        let p3 = unsafe { allocator.realloc(p2, layout, 8192) };
+        assert!(!p3.is_null());
+        assert_ne!(p3, p2);
+        for i in 0..layout.size() {
+            assert_eq!(unsafe { *p3.add(i) }, i as u8);
+        }
+        unsafe {
+            allocator.dealloc(p1, layout);
+            allocator.dealloc(p3, Layout::from_size_align(8192, 8).unwrap());
+        }
+        assert_eq!(allocator.stats().free_bytes, free_size);
+        assert!(allocator.validate().is_ok());
+    }
+
+    #[test_case]
+    fn alloc_zeroed_clears_reused_block() {
+        let allocator = test_first_fit();
+        let layout = Layout::from_size_align(512, 8).unwrap();
+        let p1 = unsafe { allocator.alloc(layout) };
+        unsafe {
+            p1.write_bytes(0xff, layout.size());
+            allocator.dealloc(p1, layout);
+        }
+        let p2 = unsafe { allocator.alloc_zeroed(layout) };
+        assert_eq!(p2, p1);
+        for i in 0..layout.size() {
+            assert_eq!(unsafe { *p2.add(i) }, 0);
+        }
+        unsafe { allocator.dealloc(p2, layout) };
+    }
+
+    #[test_case]
+    fn red_zones_detect_overflow_and_underflow() {
+        let allocator = test_first_fit();
+        let layout = Layout::from_size_align(40, 8).unwrap();
+        let errors = allocator.debug_error_count();
+        let p = allocator.alloc_with_red_zones(layout);
+        unsafe {
+            p.write_bytes(0, layout.size());
+            allocator.dealloc_with_red_zones(p, layout);
+        }
+        assert_eq!(allocator.debug_error_count(), errors);
+        let p = allocator.alloc_with_red_zones(layout);
+        unsafe {
+            p.sub(1).write(0);
+            p.add(layout.size()).write(0);
+            allocator.dealloc_with_red_zones(p, layout);
+        }
+        assert_eq!(allocator.debug_error_count(), errors + 2);
+        assert!(allocator.validate().is_ok());
+    }
+
+    #[test_case]
+    fn freed_memory_is_poisoned() {
+        let allocator = test_first_fit();
+        let layout = Layout::from_size_align(64, 8).unwrap();
+        let p = allocator.alloc_with_red_zones(layout);
+        unsafe {
+            p.write_bytes(0, layout.size());
+            allocator.dealloc_with_red_zones(p, layout);
+        }
+        for i in 0..layout.size() {
+            assert_eq!(unsafe { *p.add(i) }, POISON_BYTE);
+        }
+    }
+
+    #[test_case]
+    fn double_free_is_detected() {
+        let allocator = test_first_fit();
+        let layout = Layout::from_size_align(64, 8).unwrap();
+        let free_size = allocator.stats().free_bytes;
+        let errors = allocator.debug_error_count();
+        let p = allocator.alloc_with_red_zones(layout);
+        unsafe {
+            allocator.dealloc_with_red_zones(p, layout);
+            allocator.dealloc_with_red_zones(p, layout);
+        }
+        assert_eq!(allocator.debug_error_count(), errors + 1);
+        assert_eq!(allocator.stats().free_bytes, free_size);
+        assert!(allocator.validate().is_ok());
+    }
+
+    #[test_case]
+    fn red_zones_reject_oversized_layout() {
+        let allocator = test_first_fit();
+        let layout = Layout::from_size_align(isize::MAX as usize - 7, 8).unwrap();
+        assert!(allocator.alloc_with_red_zones(layout).is_null());
+    }
+}
@@ -0,0 +1,374 @@
+use crate::allocator::HeapAllocator;
+use crate::allocator::HeapStats;
+use crate::mutex::Mutex;
+use crate::result::Error;
+use crate::result::Result;
+use alloc::alloc::GlobalAlloc;
+use alloc::alloc::Layout;
+use core::cmp::max;
+use core::cmp::min;
+use core::ptr::null_mut;
+
+const MIN_ORDER: usize = 6; // 64バイト
+const MAX_ORDER: usize = 30; // 1GiB
+const NUM_ORDERS: usize = MAX_ORDER - MIN_ORDER + 1;
+const MIN_BLOCK_SIZE: usize = 1 << MIN_ORDER;
+const MAX_ZONES: usize = 32;
+const TAG_FREE: u8 = 0x80;
+
+// 空きブロックの先頭に置く、次数ごとの双方向リストのノード
+struct FreeBlock {
+    prev: *mut FreeBlock,
+    next: *mut FreeBlock,
+}
+
+// add_free_regionで渡された1つの連続領域。
+// 最小ブロックごとに1バイトのタグを持ち、空きブロックの先頭には TAG_FREE | 次数 を書いておく。
+// これで、バディが空いているかをリストを辿らずに判定できる
+#[derive(Clone, Copy)]
+struct Zone {
+    start: usize,
+    end: usize,
+    tags: *mut u8,
+}
+
+impl Zone {
+    const EMPTY: Zone = Zone {
+        start: 0,
+        end: 0,
+        tags: null_mut(),
+    };
+
+    fn contains(&self, addr: usize, size: usize) -> bool {
+        self.start <= addr && addr + size <= self.end
+    }
+
+    fn tag(&self, addr: usize) -> u8 {
+        unsafe { *self.tags.add((addr - self.start) / MIN_BLOCK_SIZE) }
+    }
+
+    fn set_tag(&self, addr: usize, tag: u8) {
+        unsafe { *self.tags.add((addr - self.start) / MIN_BLOCK_SIZE) = tag }
+    }
+}
+
+struct BuddyState {
+    free_lists: [*mut FreeBlock; NUM_ORDERS],
+    zones: [Zone; MAX_ZONES],
+    num_zones: usize,
+    total_bytes: usize,
+    free_bytes: usize,
+    free_block_count: usize,
+    allocated_block_count: usize,
+    allocation_count: usize,
+    deallocation_count: usize,
+}
+
+unsafe impl Send for BuddyState {}
+
+impl BuddyState {
+    fn zone_of(&self, addr: usize, size: usize) -> Option<&Zone> {
+        self.zones[..self.num_zones]
+            .iter()
+            .find(|z| z.contains(addr, size))
+    }
+
+    unsafe fn push(&mut self, addr: usize, order: usize) {
+        let zone = *self.zone_of(addr, 1 << order).unwrap();
+        zone.set_tag(addr, TAG_FREE | order as u8);
+        let block = addr as *mut FreeBlock;
+        let head = &mut self.free_lists[order - MIN_ORDER];
+        block.write(FreeBlock {
+            prev: null_mut(),
+            next: *head,
+        });
+        if !head.is_null() {
+            (**head).prev = block;
+        }
+        *head = block;
+        self.free_bytes += 1 << order;
+        self.free_block_count += 1;
+    }
+
+    unsafe fn remove(&mut self, addr: usize, order: usize) {
+        let zone = *self.zone_of(addr, 1 << order).unwrap();
+        zone.set_tag(addr, 0);
+        let block = addr as *mut FreeBlock;
+        if (*block).prev.is_null() {
+            self.free_lists[order - MIN_ORDER] = (*block).next;
+        } else {
+            (*(*block).prev).next = (*block).next;
+        }
+        if !(*block).next.is_null() {
+            (*(*block).next).prev = (*block).prev;
+        }
+        self.free_bytes -= 1 << order;
+        self.free_block_count -= 1;
+    }
+
+    fn is_free(&self, addr: usize, order: usize) -> bool {
+        match self.zone_of(addr, 1 << order) {
+            Some(zone) => zone.tag(addr) == TAG_FREE | order as u8,
+            None => false,
+        }
+    }
+
+    // バディが同じ次数で空いていて、結合後のブロックも1つの領域に収まるか
+    fn can_merge(&self, addr: usize, order: usize) -> bool {
+        let buddy = addr ^ (1 << order);
+        order < MAX_ORDER
+            && self.is_free(buddy, order)
+            && self.zone_of(min(addr, buddy), 2 << order).is_some()
+    }
+
+    fn stats(&self) -> HeapStats {
+        let largest_free_order = (MIN_ORDER..=MAX_ORDER)
+            .rev()
+            .find(|o| !self.free_lists[o - MIN_ORDER].is_null());
+        HeapStats {
+            total_bytes: self.total_bytes,
+            free_bytes: self.free_bytes,
+            used_bytes: self.total_bytes - self.free_bytes,
+            largest_free_block: largest_free_order.map_or(0, |o| 1 << o),
+            block_count: self.free_block_count + self.allocated_block_count,
+            free_block_count: self.free_block_count,
+            allocation_count: self.allocation_count,
+            deallocation_count: self.deallocation_count,
+        }
+    }
+
+    unsafe fn alloc(&mut self, order: usize) -> *mut u8 {
+        let Some(mut current) =
+            (order..=MAX_ORDER).find(|o| !self.free_lists[o - MIN_ORDER].is_null())
+        else {
+            return null_mut();
+        };
+        let addr = self.free_lists[current - MIN_ORDER] as usize;
+        self.remove(addr, current);
+        // 大きなブロックを半分ずつに割り、後ろ半分を空きリストに戻す
+        while current > order {
+            current -= 1;
+            self.push(addr + (1 << current), current);
+        }
+        self.allocated_block_count += 1;
+        self.allocation_count += 1;
+        addr as *mut u8
+    }
+
+    unsafe fn dealloc(&mut self, addr: usize, order: usize) {
+        let mut addr = addr;
+        let mut order = order;
+        // バディも空いていれば、結合して1つ上の次数に移る
+        while self.can_merge(addr, order) {
+            let buddy = addr ^ (1 << order);
+            self.remove(buddy, order);
+            addr = min(addr, buddy);
+            order += 1;
+        }
+        self.push(addr, order);
+        self.allocated_block_count -= 1;
+        self.deallocation_count += 1;
+    }
+}
+
+pub struct BuddyAllocator {
+    state: Mutex<BuddyState>,
+}
+
+pub static BUDDY_ALLOCATOR: BuddyAllocator = BuddyAllocator::new();
+
+fn order_of(layout: &Layout) -> Option<usize> {
+    let size =
+        max(max(layout.size(), layout.align()), MIN_BLOCK_SIZE).checked_next_power_of_two()?;
+    let order = size.trailing_zeros() as usize;
+    if order > MAX_ORDER {
+        None
+    } else {
+        Some(order)
+    }
+}
+
+impl BuddyAllocator {
+    pub const fn new() -> Self {
+        Self {
+            state: Mutex::new(BuddyState {
+                free_lists: [null_mut(); NUM_ORDERS],
+                zones: [Zone::EMPTY; MAX_ZONES],
+                num_zones: 0,
+                total_bytes: 0,
+                free_bytes: 0,
+                free_block_count: 0,
+                allocated_block_count: 0,
+                allocation_count: 0,
+                deallocation_count: 0,
+            }),
+        }
+    }
+}
+
+impl Default for BuddyAllocator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+unsafe impl GlobalAlloc for BuddyAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        match order_of(&layout) {
+            Some(order) => self.state.lock().alloc(order),
+            None => null_mut(),
+        }
+    }
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        if let Some(order) = order_of(&layout) {
+            self.state.lock().dealloc(ptr as usize, order);
+        }
+    }
+}
+
+impl HeapAllocator for BuddyAllocator {
+    // 領域の先頭にタグ配列を置き、残りを自然にアラインされた2のべき乗のブロックに分けて登録する。
+    // 領域の表が埋まっていれば、その領域は使えないのでOutOfRangeを返す
+    fn add_free_region(&self, start_addr: usize, size: usize) -> Result<()> {
+        let start = start_addr.next_multiple_of(MIN_BLOCK_SIZE);
+        let end = (start_addr + size) & !(MIN_BLOCK_SIZE - 1);
+        if end <= start {
+            return Err(Error::InvalidParameter);
+        }
+        let num_tags = (end - start) / MIN_BLOCK_SIZE;
+        let mut addr = (start + num_tags).next_multiple_of(MIN_BLOCK_SIZE);
+        if addr + MIN_BLOCK_SIZE > end {
+            return Err(Error::InvalidParameter);
+        }
+        let mut state = self.state.lock();
+        if state.num_zones >= MAX_ZONES {
+            return Err(Error::OutOfRange);
+        }
+        let tags = start as *mut u8;
+        unsafe { tags.write_bytes(0, num_tags) };
+        let num_zones = state.num_zones;
+        state.zones[num_zones] = Zone { start, end, tags };
+        state.num_zones += 1;
+        while addr + MIN_BLOCK_SIZE <= end {
+            let order = min(
+                min(addr.trailing_zeros() as usize, MAX_ORDER),
+                (usize::BITS - 1 - (end - addr).leading_zeros()) as usize,
+            );
+            unsafe { state.push(addr, order) };
+            state.total_bytes += 1 << order;
+            addr += 1 << order;
+        }
+        Ok(())
+    }
+
+    fn stats(&self) -> HeapStats {
+        self.state.lock().stats()
+    }
+
+    // すべての空きリストを辿り、アライン・タグ・リンク・合計サイズの整合性と、
+    // 結合し忘れたバディがないことを確かめる
+    fn validate(&self) -> Result<HeapStats> {
+        let state = self.state.lock();
+        let mut free_bytes = 0;
+        let mut free_block_count = 0;
+        for order in MIN_ORDER..=MAX_ORDER {
+            let mut prev = null_mut();
+            let mut block = state.free_lists[order - MIN_ORDER];
+            while !block.is_null() {
+                let addr = block as usize;
+                if addr % (1 << order) != 0
+                    || !state.is_free(addr, order)
+                    || unsafe { (*block).prev } != prev
+                    || state.can_merge(addr, order)
+                {
+                    return Err(Error::HeapCorruption(addr));
+                }
+                free_bytes += 1 << order;
+                free_block_count += 1;
+                prev = block;
+                block = unsafe { (*block).next };
+            }
+        }
+        if free_bytes != state.free_bytes || free_block_count != state.free_block_count {
+            return Err(Error::HeapCorruption(0));
+        }
+        // 検証したのと同じ状態から、ロックを持ったまま統計を作る
+        Ok(state.stats())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::BuddyAllocator;
+    use super::MAX_ZONES;
+    use super::MIN_BLOCK_SIZE;
+    use crate::allocator::test_suite;
+    use crate::allocator::HeapAllocator;
+    use crate::frame_allocator::FRAME_ALLOCATOR;
+    use crate::frame_allocator::FRAME_SIZE;
+    use crate::result::Error;
+    use alloc::alloc::GlobalAlloc;
+    use alloc::alloc::Layout;
+    use core::sync::atomic::AtomicBool;
+
+    static TEST_BUDDY: BuddyAllocator = BuddyAllocator::new();
+    static TEST_BUDDY_INITIALIZED: AtomicBool = AtomicBool::new(false);
+
+    fn test_buddy() -> &'static BuddyAllocator {
+        test_suite::init_test_heap(&TEST_BUDDY, &TEST_BUDDY_INITIALIZED)
+    }
+
+    #[test_case]
+    fn freed_block_is_reused() {
+        test_suite::freed_block_is_reused(test_buddy());
+    }
+
+    #[test_case]
+    fn free_size_is_restored_after_mixed_alloc_and_free() {
+        test_suite::free_size_is_restored_after_mixed_alloc_and_free(test_buddy());
+    }
+
+    #[test_case]
+    fn stats_track_allocations() {
+        test_suite::stats_track_allocations(test_buddy());
+    }
+
+    #[test_case]
+    fn buddies_are_merged_back() {
+        let buddy = test_buddy();
+        let before = buddy.stats();
+        let layout = Layout::from_size_align(64, 64).unwrap();
+        let a = unsafe { buddy.alloc(layout) };
+        let b = unsafe { buddy.alloc(layout) };
+        assert_eq!(a as usize ^ 64, b as usize);
+        unsafe {
+            buddy.dealloc(a, layout);
+            buddy.dealloc(b, layout);
+        }
+        let after = buddy.validate().expect("Heap is corrupted");
+        assert_eq!(after.free_block_count, before.free_block_count);
+        assert_eq!(after.largest_free_block, before.largest_free_block);
+    }
+
+    // 領域の表があふれたら、追加できなかった領域をエラーで知らせる
+    #[test_case]
+    fn regions_beyond_zone_table_are_rejected() {
+        // タグ2個と最小ブロック1個が入る、最小の領域
+        const REGION_SIZE: usize = MIN_BLOCK_SIZE * 2;
+        static BUDDY: BuddyAllocator = BuddyAllocator::new();
+        let num_frames = ((MAX_ZONES + 1) * REGION_SIZE).div_ceil(FRAME_SIZE);
+        let addr = FRAME_ALLOCATOR
+            .alloc_contiguous(num_frames, FRAME_SIZE)
+            .expect("Out of frames");
+        for i in 0..MAX_ZONES {
+            BUDDY.add_free_region(addr + i * REGION_SIZE, REGION_SIZE).unwrap();
+        }
+        assert_eq!(
+            BUDDY.add_free_region(addr + MAX_ZONES * REGION_SIZE, REGION_SIZE),
+            Err(Error::OutOfRange)
+        );
+        assert_eq!(BUDDY.add_free_region(addr, MIN_BLOCK_SIZE), Err(Error::InvalidParameter));
+        assert_eq!(BUDDY.stats().total_bytes, MAX_ZONES * MIN_BLOCK_SIZE);
+        FRAME_ALLOCATOR.free_contiguous(addr, num_frames).unwrap();
+    }
+}
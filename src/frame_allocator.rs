@@ -0,0 +1,313 @@
+use crate::mutex::Mutex;
+use crate::result::Error;
+use crate::result::Result;
+use crate::uefi::EfiMemoryType;
+use crate::uefi::MemoryDescriptor;
+use crate::uefi::MemoryMapHolder;
+use crate::x86::read_rsp;
+use core::cmp::max;
+use core::cmp::min;
+use core::ptr::null_mut;
+use core::sync::atomic::AtomicBool;
+use core::sync::atomic::Ordering;
+
+pub const FRAME_SIZE: usize = 4096;
+const BITS_PER_ENTRY: usize = u64::BITS as usize;
+
+// ブートサービスを抜けた直後は、まだファームウェアのGDT、IDTとページテーブルを使っていて、
+// それらはBOOT_SERVICES_*の領域にある。そのため最初はCONVENTIONAL_MEMORYだけを使い、
+// カーネル自身のものに切り替えてからrelease_boot_services_memoryで残りを解放する
+fn is_boot_services_memory(memory_type: EfiMemoryType) -> bool {
+    matches!(
+        memory_type,
+        EfiMemoryType::BOOT_SERVICES_CODE | EfiMemoryType::BOOT_SERVICES_DATA
+    )
+}
+
+fn conventional_descriptors(
+    memory_map: &MemoryMapHolder,
+) -> impl Iterator<Item = &MemoryDescriptor> {
+    memory_map
+        .iter()
+        .filter(|e| e.memory_type() == EfiMemoryType::CONVENTIONAL_MEMORY)
+}
+
+fn contains_stack(descriptor: &MemoryDescriptor) -> bool {
+    let rsp = read_rsp();
+    descriptor.physical_start() <= rsp && rsp < descriptor.physical_end()
+}
+
+// 1ビットが1フレームに対応するビットマップ。ビットが立っていれば使用中
+struct FrameBitmap {
+    bitmap: *mut u64,
+    num_frames: usize,
+    free_frames: usize,
+    next_search_frame: usize,
+}
+
+unsafe impl Send for FrameBitmap {}
+
+impl FrameBitmap {
+    fn is_used(&self, frame: usize) -> bool {
+        let entry = unsafe { *self.bitmap.add(frame / BITS_PER_ENTRY) };
+        entry & (1 << (frame % BITS_PER_ENTRY)) != 0
+    }
+
+    fn set_used(&mut self, frame: usize, used: bool) {
+        if frame >= self.num_frames || self.is_used(frame) == used {
+            return;
+        }
+        let entry = unsafe { &mut *self.bitmap.add(frame / BITS_PER_ENTRY) };
+        if used {
+            *entry |= 1 << (frame % BITS_PER_ENTRY);
+            self.free_frames -= 1;
+        } else {
+            *entry &= !(1 << (frame % BITS_PER_ENTRY));
+            self.free_frames += 1;
+        }
+    }
+
+    fn set_range_used(&mut self, first_frame: usize, num_frames: usize, used: bool) {
+        let end_frame = min(first_frame.saturating_add(num_frames), self.num_frames);
+        for frame in first_frame..end_frame {
+            self.set_used(frame, used);
+        }
+    }
+
+    fn find_free_range(
+        &self,
+        num_frames: usize,
+        align_frames: usize,
+        start: usize,
+    ) -> Option<usize> {
+        let mut frame = start.next_multiple_of(align_frames);
+        while frame + num_frames <= self.num_frames {
+            // 全部使用中のエントリはまとめて読み飛ばす
+            if frame % BITS_PER_ENTRY == 0
+                && unsafe { *self.bitmap.add(frame / BITS_PER_ENTRY) } == u64::MAX
+            {
+                frame = (frame + BITS_PER_ENTRY).next_multiple_of(align_frames);
+                continue;
+            }
+            match (frame..frame + num_frames).find(|f| self.is_used(*f)) {
+                Some(used) => frame = (used + 1).next_multiple_of(align_frames),
+                None => return Some(frame),
+            }
+        }
+        None
+    }
+
+    fn alloc(&mut self, num_frames: usize, align_frames: usize) -> Result<usize> {
+        if num_frames == 0 || !align_frames.is_power_of_two() {
+            return Err(Error::InvalidParameter);
+        }
+        if num_frames > self.free_frames {
+            return Err(Error::OutOfMemory);
+        }
+        let first_frame = self
+            .find_free_range(num_frames, align_frames, self.next_search_frame)
+            .or_else(|| self.find_free_range(num_frames, align_frames, 0))
+            .ok_or(Error::OutOfMemory)?;
+        self.set_range_used(first_frame, num_frames, true);
+        if num_frames == 1 {
+            self.next_search_frame = first_frame + 1;
+        }
+        Ok(first_frame)
+    }
+
+    fn free(&mut self, first_frame: usize, num_frames: usize) -> Result<()> {
+        if first_frame.saturating_add(num_frames) > self.num_frames {
+            return Err(Error::OutOfRange);
+        }
+        // 二重解放は、どのフレームも解放しないうちに検出する
+        if (first_frame..first_frame + num_frames).any(|f| !self.is_used(f)) {
+            return Err(Error::InvalidParameter);
+        }
+        self.set_range_used(first_frame, num_frames, false);
+        self.next_search_frame = min(self.next_search_frame, first_frame);
+        Ok(())
+    }
+}
+
+pub struct PhysicalFrameAllocator {
+    bitmap: Mutex<FrameBitmap>,
+    boot_services_memory_released: AtomicBool,
+}
+
+pub static FRAME_ALLOCATOR: PhysicalFrameAllocator = PhysicalFrameAllocator {
+    bitmap: Mutex::new(FrameBitmap {
+        bitmap: null_mut(),
+        num_frames: 0,
+        free_frames: 0,
+        next_search_frame: 0,
+    }),
+    boot_services_memory_released: AtomicBool::new(false),
+};
+
+impl PhysicalFrameAllocator {
+    // ブートサービスを抜けた後のメモリマップから、CONVENTIONAL_MEMORYだけを使うように初期化する。
+    // ビットマップは、後で解放するBOOT_SERVICES_*の領域も含めた大きさにして、
+    // 収まる大きさの最初のCONVENTIONAL_MEMORYの領域に置く
+    pub fn init_with_mmap(&self, memory_map: &MemoryMapHolder) -> Result<()> {
+        let end_addr = memory_map
+            .iter()
+            .filter(|e| {
+                e.memory_type() == EfiMemoryType::CONVENTIONAL_MEMORY
+                    || is_boot_services_memory(e.memory_type())
+            })
+            .map(|e| e.physical_end() as usize)
+            .max()
+            .ok_or(Error::OutOfMemory)?;
+        let num_frames = end_addr / FRAME_SIZE;
+        let num_entries = num_frames.div_ceil(BITS_PER_ENTRY);
+        let bitmap_frames = (num_entries * 8).div_ceil(FRAME_SIZE);
+        let bitmap_descriptor = conventional_descriptors(memory_map)
+            .find(|e| e.physical_start() != 0 && e.number_of_pages() as usize >= bitmap_frames)
+            .ok_or(Error::OutOfMemory)?;
+        let bitmap_addr = bitmap_descriptor.physical_start() as usize;
+
+        let mut bitmap = self.bitmap.lock();
+        bitmap.bitmap = bitmap_addr as *mut u64;
+        bitmap.num_frames = num_frames;
+        bitmap.next_search_frame = 0;
+        for i in 0..num_entries {
+            unsafe { bitmap.bitmap.add(i).write(u64::MAX) };
+        }
+        bitmap.free_frames = 0;
+        for e in conventional_descriptors(memory_map) {
+            set_descriptor_used(&mut bitmap, e, false);
+        }
+        bitmap.set_range_used(frame_of(bitmap_addr), bitmap_frames, true);
+        // NULLポインタと区別できるよう、0番地のフレームは使わない
+        bitmap.set_used(0, true);
+        Ok(())
+    }
+
+    // カーネルのGDT、IDTとページテーブルに切り替えた後に呼び、BOOT_SERVICES_*の領域を解放する。
+    // 今動いているスタックはBOOT_SERVICES_DATAの中にあるので、その領域だけは使わない
+    pub fn release_boot_services_memory(&self, memory_map: &MemoryMapHolder) {
+        let mut bitmap = self.bitmap.lock();
+        for e in memory_map
+            .iter()
+            .filter(|e| is_boot_services_memory(e.memory_type()) && !contains_stack(e))
+        {
+            set_descriptor_used(&mut bitmap, e, false);
+        }
+        bitmap.set_used(0, true);
+        self.boot_services_memory_released.store(true, Ordering::Relaxed);
+    }
+
+    // これより前に確保したフレームは、すべてCONVENTIONAL_MEMORYにある
+    pub fn is_boot_services_memory_released(&self) -> bool {
+        self.boot_services_memory_released.load(Ordering::Relaxed)
+    }
+
+    pub fn reserve(&self, start_addr: usize, size: usize) {
+        let first_frame = frame_of(start_addr);
+        let end_frame = (start_addr + size).div_ceil(FRAME_SIZE);
+        self.bitmap
+            .lock()
+            .set_range_used(first_frame, end_frame - first_frame, true);
+    }
+
+    pub fn alloc_frame(&self) -> Result<usize> {
+        self.alloc_contiguous(1, FRAME_SIZE)
+    }
+
+    // 物理的に連続したnum_frames個のフレームを、alignバイト境界から確保する
+    pub fn alloc_contiguous(&self, num_frames: usize, align: usize) -> Result<usize> {
+        let align_frames = max(align, FRAME_SIZE) / FRAME_SIZE;
+        let first_frame = self.bitmap.lock().alloc(num_frames, align_frames)?;
+        Ok(first_frame * FRAME_SIZE)
+    }
+
+    pub fn free_frame(&self, addr: usize) -> Result<()> {
+        self.free_contiguous(addr, 1)
+    }
+
+    pub fn free_contiguous(&self, addr: usize, num_frames: usize) -> Result<()> {
+        if addr % FRAME_SIZE != 0 {
+            return Err(Error::InvalidParameter);
+        }
+        self.bitmap.lock().free(frame_of(addr), num_frames)
+    }
+
+    pub fn free_frames(&self) -> usize {
+        self.bitmap.lock().free_frames
+    }
+
+    pub fn total_frames(&self) -> usize {
+        self.bitmap.lock().num_frames
+    }
+}
+
+fn frame_of(addr: usize) -> usize {
+    addr / FRAME_SIZE
+}
+
+fn set_descriptor_used(bitmap: &mut FrameBitmap, descriptor: &MemoryDescriptor, used: bool) {
+    bitmap.set_range_used(
+        frame_of(descriptor.physical_start() as usize),
+        descriptor.number_of_pages() as usize,
+        used,
+    );
+}
+
+#[cfg(test)]
+mod test {
+    use super::frame_of;
+    use super::FRAME_ALLOCATOR;
+    use super::FRAME_SIZE;
+    use crate::result::Error;
+    use crate::x86::read_cr3;
+    use crate::x86::read_rsp;
+
+    #[test_case]
+    fn alloc_and_free_single_frames() {
+        let free_frames = FRAME_ALLOCATOR.free_frames();
+        let a = FRAME_ALLOCATOR.alloc_frame().expect("Out of frames");
+        let b = FRAME_ALLOCATOR.alloc_frame().expect("Out of frames");
+        assert_ne!(a, b);
+        assert_ne!(a, 0);
+        assert_eq!(a % FRAME_SIZE, 0);
+        assert_eq!(b % FRAME_SIZE, 0);
+        assert_eq!(FRAME_ALLOCATOR.free_frames(), free_frames - 2);
+        unsafe { (a as *mut u8).write_bytes(0xcc, FRAME_SIZE) };
+        FRAME_ALLOCATOR.free_frame(a).unwrap();
+        FRAME_ALLOCATOR.free_frame(b).unwrap();
+        assert_eq!(FRAME_ALLOCATOR.free_frames(), free_frames);
+    }
+
+    #[test_case]
+    fn alloc_contiguous_frames_with_alignment() {
+        const NUM_FRAMES: usize = 16;
+        const ALIGN: usize = 2 * 1024 * 1024;
+        let free_frames = FRAME_ALLOCATOR.free_frames();
+        let addr = FRAME_ALLOCATOR
+            .alloc_contiguous(NUM_FRAMES, ALIGN)
+            .expect("Out of frames");
+        assert_eq!(addr % ALIGN, 0);
+        assert_eq!(FRAME_ALLOCATOR.free_frames(), free_frames - NUM_FRAMES);
+        FRAME_ALLOCATOR.free_contiguous(addr, NUM_FRAMES).unwrap();
+        assert_eq!(FRAME_ALLOCATOR.free_frames(), free_frames);
+        assert_eq!(
+            FRAME_ALLOCATOR.alloc_contiguous(free_frames + 1, FRAME_SIZE),
+            Err(Error::OutOfMemory)
+        );
+    }
+
+    #[test_case]
+    fn double_free_is_rejected() {
+        let a = FRAME_ALLOCATOR.alloc_frame().expect("Out of frames");
+        FRAME_ALLOCATOR.free_frame(a).unwrap();
+        assert_eq!(FRAME_ALLOCATOR.free_frame(a), Err(Error::InvalidParameter));
+    }
+
+    #[test_case]
+    fn frames_in_use_are_not_free() {
+        let bitmap = FRAME_ALLOCATOR.bitmap.lock();
+        assert!(bitmap.is_used(0));
+        assert!(bitmap.is_used(frame_of(read_rsp() as usize)));
+        assert!(bitmap.is_used(frame_of(read_cr3() as usize)));
+    }
+}
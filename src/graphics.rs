@@ -1,3 +1,4 @@
+use crate::result::Error;
 use crate::result::Result;
 use core::cmp::min;
 
@@ -54,7 +55,7 @@ fn draw_point<T: Bitmap>(
     x: i64,
     y: i64,
 ) -> Result<()> {
-    *(buf.pixel_at_mut(x, y).ok_or("Out of bounds")?) = color;
+    *(buf.pixel_at_mut(x, y).ok_or(Error::OutOfBounds)?) = color;
     Ok(())
 }
 
@@ -70,7 +71,7 @@ pub fn fill_rect<T: Bitmap>(
         || !buf.is_in_y_range(py)
         || !buf.is_in_x_range(px + width - 1)
         || !buf.is_in_y_range(py + height - 1) {
-        return Err("Out of bounds");
+        return Err(Error::OutOfBounds);
     }
     for y in py..py + height {
         for x in px..px + width {
@@ -109,7 +110,7 @@ fn draw_line<T: Bitmap>(
         || !buf.is_in_y_range(y0)
         || !buf.is_in_y_range(y1)
     {
-        return Err("Out of bounds");
+        return Err(Error::OutOfBounds);
     }
     let dx = (x1 - x0).abs();
     let sx = (x1 - x0).signum();
@@ -207,4 +208,181 @@ pub fn draw_test_pattern<T: Bitmap>(buf: &mut T) {
     }
     draw_str_fg(buf, left, h * colors.len() as i64, 0x00ff00, "0123456789");
     draw_str_fg(buf, left, h * colors.len() as i64 + 16, 0x00ff00, "ABCDEF");
-}
\ No newline at end of file
+}
+pub const CURSOR_WIDTH: usize = 12;
+pub const CURSOR_HEIGHT: usize = 19;
+// '@'は縁、'.'は中身、空白は透明
+const CURSOR_SPRITE: [&str; CURSOR_HEIGHT] = [
+    "@           ",
+    "@@          ",
+    "@.@         ",
+    "@..@        ",
+    "@...@       ",
+    "@....@      ",
+    "@.....@     ",
+    "@......@    ",
+    "@.......@   ",
+    "@........@  ",
+    "@.........@ ",
+    "@..........@",
+    "@......@@@@@",
+    "@...@..@    ",
+    "@..@ @..@   ",
+    "@.@  @..@   ",
+    "@@    @..@  ",
+    "      @..@  ",
+    "       @@   ",
+];
+const CURSOR_EDGE_COLOR: u32 = 0x000000;
+const CURSOR_FILL_COLOR: u32 = 0xffffff;
+
+// 画面に重ねて描くマウスカーソル。描く前に下の画素を保存し、消すときに書き戻す
+pub struct MouseCursor {
+    x: i64,
+    y: i64,
+    saved: [u32; CURSOR_WIDTH * CURSOR_HEIGHT],
+    is_visible: bool,
+}
+
+impl MouseCursor {
+    pub const fn new(x: i64, y: i64) -> Self {
+        Self {
+            x,
+            y,
+            saved: [0; CURSOR_WIDTH * CURSOR_HEIGHT],
+            is_visible: false,
+        }
+    }
+
+    pub fn position(&self) -> (i64, i64) {
+        (self.x, self.y)
+    }
+
+    pub fn is_visible(&self) -> bool {
+        self.is_visible
+    }
+
+    // 画面の外にはみ出す部分は描かない
+    pub fn show<T: Bitmap>(&mut self, buf: &mut T) {
+        if self.is_visible {
+            return;
+        }
+        for (dy, row) in CURSOR_SPRITE.iter().enumerate() {
+            for (dx, c) in row.chars().enumerate() {
+                let color = match c {
+                    '@' => CURSOR_EDGE_COLOR,
+                    '.' => CURSOR_FILL_COLOR,
+                    _ => continue,
+                };
+                if let Some(pixel) = buf.pixel_at_mut(self.x + dx as i64, self.y + dy as i64) {
+                    self.saved[dy * CURSOR_WIDTH + dx] = *pixel;
+                    *pixel = color;
+                }
+            }
+        }
+        self.is_visible = true;
+    }
+
+    pub fn hide<T: Bitmap>(&mut self, buf: &mut T) {
+        if !self.is_visible {
+            return;
+        }
+        for (dy, row) in CURSOR_SPRITE.iter().enumerate() {
+            for (dx, c) in row.chars().enumerate() {
+                if c == ' ' {
+                    continue;
+                }
+                if let Some(pixel) = buf.pixel_at_mut(self.x + dx as i64, self.y + dy as i64) {
+                    *pixel = self.saved[dy * CURSOR_WIDTH + dx];
+                }
+            }
+        }
+        self.is_visible = false;
+    }
+
+    // カーソルの先端が画面の中に収まるように動かす
+    pub fn move_to<T: Bitmap>(&mut self, buf: &mut T, x: i64, y: i64) {
+        let was_visible = self.is_visible;
+        self.hide(buf);
+        self.x = x.clamp(0, buf.width() - 1);
+        self.y = y.clamp(0, buf.height() - 1);
+        if was_visible {
+            self.show(buf);
+        }
+    }
+
+    pub fn move_by<T: Bitmap>(&mut self, buf: &mut T, dx: i64, dy: i64) {
+        self.move_to(buf, self.x + dx, self.y + dy);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Bitmap;
+    use super::MouseCursor;
+    use super::CURSOR_WIDTH;
+    use alloc::vec::Vec;
+
+    struct TestBitmap {
+        width: i64,
+        height: i64,
+        pixels: Vec<u32>,
+    }
+
+    impl TestBitmap {
+        fn new(width: i64, height: i64) -> Self {
+            // 画素ごとに違う値にして、書き戻しの位置ずれも検出できるようにする
+            let pixels = (0..width * height).map(|i| i as u32).collect();
+            Self {
+                width,
+                height,
+                pixels,
+            }
+        }
+    }
+
+    impl Bitmap for TestBitmap {
+        fn bytes_per_pixel(&self) -> i64 {
+            4
+        }
+        fn pixels_per_line(&self) -> i64 {
+            self.width
+        }
+        fn width(&self) -> i64 {
+            self.width
+        }
+        fn height(&self) -> i64 {
+            self.height
+        }
+        fn buf_mut(&mut self) -> *mut u8 {
+            self.pixels.as_mut_ptr() as *mut u8
+        }
+    }
+
+    #[test_case]
+    fn cursor_restores_pixels_underneath() {
+        let mut buf = TestBitmap::new(32, 32);
+        let original = buf.pixels.clone();
+        let mut cursor = MouseCursor::new(4, 4);
+        cursor.show(&mut buf);
+        assert_eq!(buf.pixels[4 * 32 + 4], 0x000000);
+        assert_ne!(buf.pixels, original);
+        cursor.move_by(&mut buf, 3, 5);
+        assert!(cursor.is_visible());
+        assert_eq!(cursor.position(), (7, 9));
+        cursor.hide(&mut buf);
+        assert_eq!(buf.pixels, original);
+    }
+
+    #[test_case]
+    fn cursor_is_clipped_at_screen_edges() {
+        let mut buf = TestBitmap::new(CURSOR_WIDTH as i64, 8);
+        let original = buf.pixels.clone();
+        let mut cursor = MouseCursor::new(0, 0);
+        cursor.show(&mut buf);
+        cursor.move_to(&mut buf, 100, -100);
+        assert_eq!(cursor.position(), (CURSOR_WIDTH as i64 - 1, 0));
+        cursor.hide(&mut buf);
+        assert_eq!(buf.pixels, original);
+    }
+}
@@ -0,0 +1,90 @@
+use crate::acpi;
+use crate::allocator::HeapAllocator;
+use crate::allocator::KERNEL_HEAP;
+use crate::frame_allocator::FRAME_ALLOCATOR;
+use crate::frame_allocator::FRAME_SIZE;
+use crate::time;
+use crate::uefi::exit_from_efi_boot_services;
+use crate::uefi::init_vram;
+use crate::uefi::EfiHandle;
+use crate::uefi::EfiSystemTable;
+use crate::uefi::MemoryMapHolder;
+use crate::x86::apic;
+use crate::x86::enable_interrupts;
+use crate::x86::gdt;
+use crate::x86::idt;
+use crate::x86::keyboard;
+use crate::x86::mouse;
+use crate::x86::paging;
+use crate::x86::paging::CacheMode;
+use crate::x86::rtc;
+use crate::x86::timer;
+use core::cmp::min;
+
+const MAX_HEAP_SIZE: usize = 64 * 1024 * 1024;
+
+// ブートサービスを抜けて、最終的なメモリマップから物理フレームとヒープを初期化し、
+// カーネル自身のGDT、IDTとページテーブルに切り替えてから、APIC、タイマ、キーボードとマウスの割り込みを受け付ける
+pub fn init_basic_runtime(
+    image_handle: EfiHandle,
+    efi_system_table: &EfiSystemTable,
+) -> MemoryMapHolder {
+    let rsdp_addr = efi_system_table
+        .acpi_rsdp_addr()
+        .expect("ACPI RSDP not found");
+    // ランタイムサービスは、ブートサービスを抜ける前に呼んでおく
+    let efi_time = efi_system_table.runtime_services().get_time().ok();
+    // フレームバッファの場所も、ブートサービスを使えるうちに調べておく
+    let frame_buffer = init_vram(efi_system_table)
+        .ok()
+        .map(|vram| vram.frame_buffer_range());
+    let mut memory_map = MemoryMapHolder::new();
+    exit_from_efi_boot_services(image_handle, efi_system_table, &mut memory_map);
+    FRAME_ALLOCATOR
+        .init_with_mmap(&memory_map)
+        .expect("Failed to initialize frame allocator");
+    init_heap();
+    gdt::init();
+    idt::init();
+    paging::init(&memory_map).expect("Failed to initialize paging");
+    // UC-なら、ファームウェアがMTRRでフレームバッファを書き込み結合にしていればそれが効く
+    if let Some((addr, size)) = frame_buffer {
+        paging::map_mmio(addr, size, CacheMode::UncachedMinus)
+            .expect("Failed to map frame buffer");
+    }
+    // ファームウェアのGDT、IDTとページテーブルを使わなくなったので、その領域も再利用する
+    FRAME_ALLOCATOR.release_boot_services_memory(&memory_map);
+    acpi::init(rsdp_addr).expect("Failed to initialize ACPI");
+    apic::init().expect("Failed to initialize APIC");
+    timer::init().expect("Failed to initialize timer");
+    // RTCが読めなければ、UEFIから得た少し前の日時で代用する
+    if let Some(now) = rtc::read_time().ok().or(efi_time) {
+        time::set_wall_clock(now);
+    }
+    // PS/2キーボードやマウスのない機種もあるので、失敗しても起動は続ける
+    if keyboard::init().is_ok() {
+        let _ = mouse::init();
+    }
+    enable_interrupts();
+    memory_map
+}
+
+// ヒープは、物理フレームアロケータから借りた連続領域に作る。
+// 連続領域が見つからなければ、半分ずつ小さくして探し直す
+fn init_heap() {
+    let mut num_frames = min(
+        FRAME_ALLOCATOR.free_frames() / 2,
+        MAX_HEAP_SIZE / FRAME_SIZE,
+    );
+    while num_frames > 0 {
+        if let Ok(addr) = FRAME_ALLOCATOR.alloc_contiguous(num_frames, FRAME_SIZE) {
+            KERNEL_HEAP
+                .backing()
+                .add_free_region(addr, num_frames * FRAME_SIZE)
+                .expect("Failed to add the heap region");
+            return;
+        }
+        num_frames /= 2;
+    }
+    panic!("No memory for the kernel heap");
+}
@@ -1,15 +1,28 @@
 #![no_std]
 #![feature(offset_of)]
+#![feature(alloc_error_handler)]
 #![feature(custom_test_frameworks)]
 #![feature(lang_items)]
+#![feature(panic_info_message)]
 #![test_runner(test_runner::test_runner)]
 #![reexport_test_harness_main = "run_unit_tests"]
 #![no_main]
 
+extern crate alloc;
+
+pub mod acpi;
 pub mod allocator;
+pub mod buddy;
+pub mod frame_allocator;
 pub mod graphics;
+pub mod init;
+pub mod mutex;
+pub mod panic;
 pub mod qemu;
+pub mod queue;
 pub mod result;
+pub mod slab;
+pub mod time;
 pub mod uefi;
 pub mod x86;
 
@@ -23,6 +36,10 @@ pub extern "C" fn eh_personality() {}
 
 #[cfg(test)]
 #[no_mangle]
-pub fn efi_main() {
+pub fn efi_main(
+    image_handle: uefi::EfiHandle,
+    efi_system_table: &uefi::EfiSystemTable,
+) {
+    init::init_basic_runtime(image_handle, efi_system_table);
     run_unit_tests()
 }
\ No newline at end of file
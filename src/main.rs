@@ -2,23 +2,37 @@
 #![no_std]
 #![no_main]
 
-use core::arch::asm;
 use core::fmt::Write;
 use core::panic::PanicInfo;
 use core::writeln;
+use testOS::allocator::HeapAllocator;
+use testOS::allocator::KERNEL_HEAP;
+use testOS::frame_allocator::FRAME_ALLOCATOR;
 use testOS::graphics::draw_test_pattern;
 use testOS::graphics::fill_rect;
 use testOS::graphics::Bitmap;
+use testOS::graphics::MouseCursor;
+use testOS::init::init_basic_runtime;
+use testOS::panic::write_panic_info;
 use testOS::uefi::init_vram;
 use  testOS::uefi::EfiHandle;
 use testOS::uefi::EfiMemoryType;
 use testOS::uefi::EfiSystemTable;
-use testOS::uefi::exit_from_efi_boot_services;
 use testOS::uefi::MemoryMapHolder;
+use testOS::uefi::VramBufferInfo;
 use testOS::uefi::VramTextWriter;
+use testOS::uefi::write_memory_map;
+use testOS::uefi::write_memory_map_summary;
 use testOS::qemu::exit_qemu;
 use testOS::qemu::QemuExitCode;
+use testOS::time::now_utc;
 use testOS::x86::hlt;
+use testOS::x86::mouse;
+use testOS::x86::serial::SerialPort;
+use testOS::x86::serial::COM1;
+
+// パニック時に画面にも出力するため、初期化済みのVRAMを覚えておく
+static mut PANIC_VRAM: Option<VramBufferInfo> = None;
 
 // EFIのエントリポイント
 #[no_mangle]
@@ -26,42 +40,89 @@ fn efi_main(
     image_handle: EfiHandle,
     efi_system_table: &EfiSystemTable,
 ) -> ! {
+    // COM1のない機種もあるので、初期化できなければシリアルには出力しない
+    let mut serial = SerialPort::new(COM1);
+    let serial_result = serial.init(115200);
+    let mut serial = serial_result.is_ok().then_some(serial);
+    if let Some(serial) = serial.as_mut() {
+        writeln!(serial, "testOS: efi_main").unwrap();
+    }
 
     let mut vram = init_vram(efi_system_table).expect("Failed to initialize VRAM");
+    unsafe {
+        PANIC_VRAM = Some(vram);
+    }
     let vw = vram.width();
     let vh = vram.height();
     fill_rect(&mut vram, 0, 0, vw, vh, 0x000000).expect("Failed to fill rect");
     draw_test_pattern(&mut vram);
     let mut w = VramTextWriter::new(&mut vram);
+    if let Err(e) = serial_result {
+        writeln!(w, "Serial port is not available: {e}").unwrap();
+    }
     for i in 0..4 {
         writeln!(w, "i = {i}").unwrap();
     }
 
     let mut memory_map = MemoryMapHolder::new();
-    let status = efi_system_table.boot_services().get_memory_map(&mut memory_map);
-    writeln!(w, "EFI_STATUS: {status:?}").unwrap();
-    let mut total_memory_page = 0;
-    for e in memory_map.iter() {
-        if e.memory_type() != EfiMemoryType::CONVENTIONAL_MEMORY {
-            continue;
-        }
-        total_memory_page += e.number_of_pages();
-        writeln!(w, "{e:?}").unwrap();
+    efi_system_table
+        .boot_services()
+        .get_memory_map(&mut memory_map)
+        .expect("Failed to get memory map");
+    writeln!(
+        w,
+        "Memory map: descriptor size = {}, version = {}",
+        memory_map.descriptor_size(),
+        memory_map.descriptor_version()
+    )
+    .unwrap();
+    if let Some(serial) = serial.as_mut() {
+        write_memory_map(serial, &memory_map).unwrap();
     }
+    write_memory_map_summary(&mut w, &memory_map).unwrap();
+    let total_memory_page = memory_map.total_pages(EfiMemoryType::CONVENTIONAL_MEMORY);
     let total_memory_size = total_memory_page * 4096 / 1024 / 1024;
     writeln!(w, "Total memory size: {total_memory_size}MiB").unwrap();
 
     //println!("Hello, world!");
-    exit_from_efi_boot_services(image_handle, efi_system_table, &mut memory_map);
+    let _memory_map = init_basic_runtime(image_handle, efi_system_table);
     writeln!(w, "Exit from EFI boot services").unwrap();
+    if let Some(serial) = serial.as_mut() {
+        writeln!(serial, "Exit from EFI boot services").unwrap();
+        writeln!(
+            serial,
+            "Free frames: {} / {}",
+            FRAME_ALLOCATOR.free_frames(),
+            FRAME_ALLOCATOR.total_frames()
+        )
+        .unwrap();
+        writeln!(serial, "Heap initialized: {:?}", KERNEL_HEAP.backing().stats()).unwrap();
+    }
+    if let Some(now) = now_utc() {
+        writeln!(w, "Current time: {now}").unwrap();
+        if let Some(serial) = serial.as_mut() {
+            writeln!(serial, "Current time: {now}").unwrap();
+        }
+    }
+    let mut cursor = MouseCursor::new(vw / 2, vh / 2);
+    cursor.show(&mut vram);
     loop {
-        unsafe {
-            asm!("hlt");
+        while let Some(event) = mouse::read_event() {
+            cursor.move_by(&mut vram, event.dx as i64, event.dy as i64);
         }
+        hlt();
     }
 }
 
 #[panic_handler]
-fn panic(_info: &PanicInfo) -> ! {
+fn panic(info: &PanicInfo) -> ! {
+    let mut serial = SerialPort::new(COM1);
+    let _ = write_panic_info(&mut serial, info);
+    if let Some(mut vram) = unsafe { PANIC_VRAM } {
+        let vw = vram.width();
+        let _ = fill_rect(&mut vram, 0, 0, vw, 32, 0x800000);
+        let mut w = VramTextWriter::new(&mut vram);
+        let _ = write_panic_info(&mut w, info);
+    }
     exit_qemu(QemuExitCode::Failure);
 }
@@ -0,0 +1,127 @@
+use crate::x86::are_interrupts_enabled;
+use crate::x86::disable_interrupts;
+use crate::x86::enable_interrupts;
+use core::cell::UnsafeCell;
+use core::hint::spin_loop;
+use core::ops::Deref;
+use core::ops::DerefMut;
+use core::sync::atomic::AtomicBool;
+use core::sync::atomic::Ordering;
+
+// スピンロック。保持している間は割り込みを禁止するので、
+// 割り込みハンドラから同じロックを取ってもデッドロックしない
+pub struct Mutex<T> {
+    locked: AtomicBool,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: Send> Sync for Mutex<T> {}
+unsafe impl<T: Send> Send for Mutex<T> {}
+
+impl<T> Mutex<T> {
+    pub const fn new(data: T) -> Self {
+        Self {
+            locked: AtomicBool::new(false),
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    pub fn lock(&self) -> MutexGuard<T> {
+        let interrupts_were_enabled = are_interrupts_enabled();
+        disable_interrupts();
+        while self
+            .locked
+            .compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            while self.locked.load(Ordering::Relaxed) {
+                spin_loop();
+            }
+        }
+        MutexGuard {
+            mutex: self,
+            interrupts_were_enabled,
+        }
+    }
+
+    pub fn try_lock(&self) -> Option<MutexGuard<T>> {
+        let interrupts_were_enabled = are_interrupts_enabled();
+        disable_interrupts();
+        if self
+            .locked
+            .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_ok()
+        {
+            Some(MutexGuard {
+                mutex: self,
+                interrupts_were_enabled,
+            })
+        } else {
+            if interrupts_were_enabled {
+                enable_interrupts();
+            }
+            None
+        }
+    }
+}
+
+pub struct MutexGuard<'a, T> {
+    mutex: &'a Mutex<T>,
+    interrupts_were_enabled: bool,
+}
+
+impl<T> Deref for MutexGuard<'_, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        unsafe { &*self.mutex.data.get() }
+    }
+}
+
+impl<T> DerefMut for MutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.mutex.data.get() }
+    }
+}
+
+impl<T> Drop for MutexGuard<'_, T> {
+    fn drop(&mut self) {
+        self.mutex.locked.store(false, Ordering::Release);
+        if self.interrupts_were_enabled {
+            enable_interrupts();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Mutex;
+    use crate::x86::are_interrupts_enabled;
+
+    #[test_case]
+    fn lock_and_unlock() {
+        let m = Mutex::new(0);
+        *m.lock() += 1;
+        *m.lock() += 1;
+        assert_eq!(*m.lock(), 2);
+    }
+
+    #[test_case]
+    fn try_lock_fails_while_locked() {
+        let m = Mutex::new(());
+        let guard = m.lock();
+        assert!(m.try_lock().is_none());
+        drop(guard);
+        assert!(m.try_lock().is_some());
+    }
+
+    #[test_case]
+    fn interrupts_are_disabled_while_locked() {
+        let m = Mutex::new(());
+        let enabled = are_interrupts_enabled();
+        {
+            let _guard = m.lock();
+            assert!(!are_interrupts_enabled());
+        }
+        assert_eq!(are_interrupts_enabled(), enabled);
+    }
+}
@@ -0,0 +1,20 @@
+use core::fmt;
+use core::panic::PanicInfo;
+
+// "PANIC at src/graphics.rs:12:34: Failed to fill rect" の形式で出力する
+pub fn write_panic_info<W: fmt::Write>(w: &mut W, info: &PanicInfo) -> fmt::Result {
+    write!(w, "PANIC")?;
+    if let Some(location) = info.location() {
+        write!(
+            w,
+            " at {}:{}:{}",
+            location.file(),
+            location.line(),
+            location.column()
+        )?;
+    }
+    match info.message() {
+        Some(message) => writeln!(w, ": {message}"),
+        None => writeln!(w),
+    }
+}
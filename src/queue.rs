@@ -0,0 +1,82 @@
+// 割り込みハンドラが積み、通常の処理が取り出すための固定長のリングバッファ。
+// 割り込みの中でヒープを使わないよう、要素は配列に直接持つ。あふれたときは新しいものを捨てる
+pub struct EventQueue<T: Copy, const N: usize> {
+    events: [Option<T>; N],
+    head: usize,
+    len: usize,
+}
+
+impl<T: Copy, const N: usize> EventQueue<T, N> {
+    pub const fn new() -> Self {
+        Self {
+            events: [None; N],
+            head: 0,
+            len: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn push(&mut self, event: T) -> bool {
+        if self.len == N {
+            return false;
+        }
+        self.events[(self.head + self.len) % N] = Some(event);
+        self.len += 1;
+        true
+    }
+
+    pub fn pop(&mut self) -> Option<T> {
+        if self.len == 0 {
+            return None;
+        }
+        let event = self.events[self.head].take();
+        self.head = (self.head + 1) % N;
+        self.len -= 1;
+        event
+    }
+}
+
+impl<T: Copy, const N: usize> Default for EventQueue<T, N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::EventQueue;
+
+    #[test_case]
+    fn queue_drops_events_when_full() {
+        let mut queue = EventQueue::<u32, 4>::new();
+        for i in 0..4 {
+            assert!(queue.push(i));
+        }
+        assert!(!queue.push(4));
+        assert_eq!(queue.len(), 4);
+        for i in 0..4 {
+            assert_eq!(queue.pop(), Some(i));
+        }
+        assert_eq!(queue.pop(), None);
+        assert!(queue.is_empty());
+    }
+
+    #[test_case]
+    fn queue_wraps_around() {
+        let mut queue = EventQueue::<u32, 3>::new();
+        for i in 0..10 {
+            assert!(queue.push(i));
+            assert!(queue.push(i + 100));
+            assert_eq!(queue.pop(), Some(i));
+            assert_eq!(queue.pop(), Some(i + 100));
+        }
+        assert!(queue.is_empty());
+    }
+}
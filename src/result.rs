@@ -1 +1,54 @@
-pub type Result<T> = core::result::Result<T, &'static str>;
\ No newline at end of file
+use crate::uefi::EfiStatus;
+use core::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    OutOfBounds,
+    OutOfRange,
+    InvalidParameter,
+    ProtocolNotFound,
+    DeviceNotFound,
+    // 物理フレームやファームウェアのプールなど、確保できるメモリが残っていない
+    OutOfMemory,
+    AlreadyMapped,
+    NotMapped,
+    Unsupported,
+    NotInitialized,
+    Timeout,
+    // デバイスが想定外のバイトを返した
+    UnexpectedResponse(u8),
+    InvalidMemoryMap,
+    // 署名で示したACPIの表が壊れている
+    InvalidAcpiTable([u8; 4]),
+    HeapCorruption(usize),
+    Efi(EfiStatus),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::OutOfBounds => write!(f, "Out of bounds"),
+            Error::OutOfRange => write!(f, "Out of range"),
+            Error::InvalidParameter => write!(f, "Invalid parameter"),
+            Error::ProtocolNotFound => write!(f, "Protocol not found"),
+            Error::DeviceNotFound => write!(f, "Device not found"),
+            Error::OutOfMemory => write!(f, "Out of memory"),
+            Error::AlreadyMapped => write!(f, "Already mapped"),
+            Error::NotMapped => write!(f, "Not mapped"),
+            Error::Unsupported => write!(f, "Unsupported"),
+            Error::NotInitialized => write!(f, "Not initialized"),
+            Error::Timeout => write!(f, "Timed out"),
+            Error::UnexpectedResponse(byte) => write!(f, "Unexpected response {byte:#04X}"),
+            Error::InvalidMemoryMap => write!(f, "Invalid memory map"),
+            Error::InvalidAcpiTable(signature) => write!(
+                f,
+                "Invalid ACPI table {}",
+                core::str::from_utf8(signature).unwrap_or("????")
+            ),
+            Error::HeapCorruption(addr) => write!(f, "Heap corruption detected at {addr:#X}"),
+            Error::Efi(status) => write!(f, "EFI error: {status}"),
+        }
+    }
+}
+
+pub type Result<T> = core::result::Result<T, Error>;
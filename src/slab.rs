@@ -0,0 +1,449 @@
+use crate::mutex::Mutex;
+use alloc::alloc::GlobalAlloc;
+use alloc::alloc::Layout;
+use core::cmp::max;
+use core::cmp::min;
+use core::mem::size_of;
+use core::ptr::copy_nonoverlapping;
+use core::ptr::null_mut;
+
+const MIN_OBJECT_SIZE: usize = 16;
+pub const MAX_OBJECT_SIZE: usize = 2048;
+const NUM_SIZE_CLASSES: usize = 8;
+const MIN_SLAB_SIZE: usize = 4096;
+// 大きなサイズクラスでも、1枚のスラブに十分な数のオブジェクトが入るようにする
+const MIN_OBJECTS_PER_SLAB: usize = 16;
+
+// 空きオブジェクトは、その領域自体を使って単方向リストにつなぐ
+struct FreeObject {
+    next: *mut FreeObject,
+}
+
+// スラブの先頭に置くヘッダ。スラブはslab_sizeにアラインされているので、
+// オブジェクトのアドレスの下位ビットを落とせばヘッダが見つかる
+struct Slab {
+    prev: *mut Slab,
+    next: *mut Slab,
+    free_list: *mut FreeObject,
+    free_count: usize,
+}
+
+struct SlabCache {
+    object_size: usize,
+    // 空きオブジェクトを持つスラブの双方向リスト
+    partial: *mut Slab,
+    empty_slabs: usize,
+    slab_count: usize,
+    objects_in_use: usize,
+}
+
+unsafe impl Send for SlabCache {}
+
+impl SlabCache {
+    const fn new(object_size: usize) -> Self {
+        Self {
+            object_size,
+            partial: null_mut(),
+            empty_slabs: 0,
+            slab_count: 0,
+            objects_in_use: 0,
+        }
+    }
+
+    fn slab_size(&self) -> usize {
+        slab_size_of(self.object_size)
+    }
+
+    fn slab_layout(&self) -> Layout {
+        Layout::from_size_align(self.slab_size(), self.slab_size()).unwrap()
+    }
+
+    fn capacity(&self) -> usize {
+        (self.slab_size() - self.first_object_offset()) / self.object_size
+    }
+
+    fn first_object_offset(&self) -> usize {
+        size_of::<Slab>().next_multiple_of(self.object_size)
+    }
+
+    unsafe fn push_partial(&mut self, slab: *mut Slab) {
+        (*slab).prev = null_mut();
+        (*slab).next = self.partial;
+        if !self.partial.is_null() {
+            (*self.partial).prev = slab;
+        }
+        self.partial = slab;
+    }
+
+    unsafe fn remove_partial(&mut self, slab: *mut Slab) {
+        if (*slab).prev.is_null() {
+            self.partial = (*slab).next;
+        } else {
+            (*(*slab).prev).next = (*slab).next;
+        }
+        if !(*slab).next.is_null() {
+            (*(*slab).next).prev = (*slab).prev;
+        }
+        (*slab).prev = null_mut();
+        (*slab).next = null_mut();
+    }
+
+    unsafe fn grow<B: GlobalAlloc>(&mut self, backing: &B) -> bool {
+        let base = backing.alloc(self.slab_layout());
+        if base.is_null() {
+            return false;
+        }
+        let slab = base as *mut Slab;
+        slab.write(Slab {
+            prev: null_mut(),
+            next: null_mut(),
+            free_list: null_mut(),
+            free_count: 0,
+        });
+        for i in (0..self.capacity()).rev() {
+            let object =
+                base.add(self.first_object_offset() + i * self.object_size) as *mut FreeObject;
+            object.write(FreeObject {
+                next: (*slab).free_list,
+            });
+            (*slab).free_list = object;
+            (*slab).free_count += 1;
+        }
+        self.push_partial(slab);
+        self.slab_count += 1;
+        self.empty_slabs += 1;
+        true
+    }
+
+    unsafe fn alloc<B: GlobalAlloc>(&mut self, backing: &B) -> *mut u8 {
+        if self.partial.is_null() && !self.grow(backing) {
+            return null_mut();
+        }
+        let slab = self.partial;
+        if (*slab).free_count == self.capacity() {
+            self.empty_slabs -= 1;
+        }
+        let object = (*slab).free_list;
+        (*slab).free_list = (*object).next;
+        (*slab).free_count -= 1;
+        if (*slab).free_count == 0 {
+            self.remove_partial(slab);
+        }
+        self.objects_in_use += 1;
+        object as *mut u8
+    }
+
+    unsafe fn dealloc<B: GlobalAlloc>(&mut self, ptr: *mut u8, backing: &B) {
+        let slab = (ptr as usize & !(self.slab_size() - 1)) as *mut Slab;
+        let object = ptr as *mut FreeObject;
+        object.write(FreeObject {
+            next: (*slab).free_list,
+        });
+        (*slab).free_list = object;
+        (*slab).free_count += 1;
+        self.objects_in_use -= 1;
+        if (*slab).free_count == 1 {
+            self.push_partial(slab);
+        }
+        if (*slab).free_count == self.capacity() {
+            // 空のスラブは1枚だけ手元に残し、それ以上はバックエンドに返す
+            if self.empty_slabs >= 1 {
+                self.remove_partial(slab);
+                backing.dealloc(slab as *mut u8, self.slab_layout());
+                self.slab_count -= 1;
+            } else {
+                self.empty_slabs += 1;
+            }
+        }
+    }
+}
+
+fn slab_size_of(object_size: usize) -> usize {
+    max(MIN_SLAB_SIZE, object_size * MIN_OBJECTS_PER_SLAB)
+}
+
+fn size_class_index(layout: &Layout) -> Option<usize> {
+    let size = max(max(layout.size(), layout.align()), MIN_OBJECT_SIZE).next_power_of_two();
+    if size > MAX_OBJECT_SIZE {
+        None
+    } else {
+        Some((size.trailing_zeros() - MIN_OBJECT_SIZE.trailing_zeros()) as usize)
+    }
+}
+
+// オブジェクトを切り出すキャッシュ。heap_debugフィーチャでは、バックエンドが
+// レッドゾーンで誤りを検出できるよう、すべての要求をバックエンドに渡す
+fn cache_index(layout: &Layout) -> Option<usize> {
+    if cfg!(feature = "heap_debug") {
+        return None;
+    }
+    size_class_index(layout)
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SlabStats {
+    pub slab_count: usize,
+    pub slab_bytes: usize,
+    pub objects_in_use: usize,
+}
+
+// 小さなオブジェクトはサイズクラスごとのスラブから切り出し、
+// それより大きな要求はバックエンドのアロケータにそのまま渡す
+pub struct SlabAllocator<B: 'static> {
+    backing: &'static B,
+    caches: [Mutex<SlabCache>; NUM_SIZE_CLASSES],
+}
+
+impl<B: GlobalAlloc> SlabAllocator<B> {
+    pub const fn new(backing: &'static B) -> Self {
+        Self {
+            backing,
+            caches: [
+                Mutex::new(SlabCache::new(16)),
+                Mutex::new(SlabCache::new(32)),
+                Mutex::new(SlabCache::new(64)),
+                Mutex::new(SlabCache::new(128)),
+                Mutex::new(SlabCache::new(256)),
+                Mutex::new(SlabCache::new(512)),
+                Mutex::new(SlabCache::new(1024)),
+                Mutex::new(SlabCache::new(2048)),
+            ],
+        }
+    }
+
+    pub fn backing(&self) -> &'static B {
+        self.backing
+    }
+
+    pub fn stats(&self) -> SlabStats {
+        let mut stats = SlabStats::default();
+        for cache in self.caches.iter() {
+            let cache = cache.lock();
+            stats.slab_count += cache.slab_count;
+            stats.slab_bytes += cache.slab_count * cache.slab_size();
+            stats.objects_in_use += cache.objects_in_use;
+        }
+        stats
+    }
+}
+
+unsafe impl<B: GlobalAlloc> GlobalAlloc for SlabAllocator<B> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        match cache_index(&layout) {
+            Some(i) => self.caches[i].lock().alloc(self.backing),
+            None => self.backing.alloc(layout),
+        }
+    }
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        match cache_index(&layout) {
+            Some(i) => self.caches[i].lock().dealloc(ptr, self.backing),
+            None => self.backing.dealloc(ptr, layout),
+        }
+    }
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        match cache_index(&layout) {
+            Some(_) => {
+                let ptr = self.alloc(layout);
+                if !ptr.is_null() {
+                    ptr.write_bytes(0, layout.size());
+                }
+                ptr
+            }
+            None => self.backing.alloc_zeroed(layout),
+        }
+    }
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let new_layout = Layout::from_size_align_unchecked(new_size, layout.align());
+        match (cache_index(&layout), cache_index(&new_layout)) {
+            // 同じサイズクラスに収まるなら、オブジェクトをそのまま使い続ける
+            (Some(i), Some(j)) if i == j => ptr,
+            // 大きなオブジェクト同士なら、バックエンドがその場で伸縮できる
+            (None, None) => self.backing.realloc(ptr, layout, new_size),
+            _ => {
+                let new_ptr = self.alloc(new_layout);
+                if !new_ptr.is_null() {
+                    copy_nonoverlapping(ptr, new_ptr, min(layout.size(), new_size));
+                    self.dealloc(ptr, layout);
+                }
+                new_ptr
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::size_class_index;
+    use super::MAX_OBJECT_SIZE;
+    #[cfg(any(not(feature = "heap_debug"), not(feature = "buddy_allocator")))]
+    use crate::allocator::HeapAllocator;
+    #[cfg(any(not(feature = "heap_debug"), not(feature = "buddy_allocator")))]
+    use crate::allocator::KERNEL_HEAP;
+    #[cfg(any(not(feature = "heap_debug"), not(feature = "buddy_allocator")))]
+    use alloc::alloc::GlobalAlloc;
+    use alloc::alloc::Layout;
+    #[cfg(not(feature = "heap_debug"))]
+    use crate::x86::rdtsc;
+    #[cfg(not(feature = "heap_debug"))]
+    use crate::x86::serial::SerialPort;
+    #[cfg(not(feature = "heap_debug"))]
+    use crate::x86::serial::COM1;
+    #[cfg(not(feature = "heap_debug"))]
+    use alloc::vec::Vec;
+    #[cfg(not(feature = "heap_debug"))]
+    use core::fmt::Write;
+
+    #[test_case]
+    fn size_classes() {
+        let class = |size, align| size_class_index(&Layout::from_size_align(size, align).unwrap());
+        assert_eq!(class(1, 1), Some(0));
+        assert_eq!(class(16, 8), Some(0));
+        assert_eq!(class(17, 8), Some(1));
+        assert_eq!(class(8, 64), Some(2));
+        assert_eq!(class(MAX_OBJECT_SIZE, 8), Some(7));
+        assert_eq!(class(MAX_OBJECT_SIZE + 1, 8), None);
+        assert_eq!(class(8, 4096), None);
+    }
+
+    #[cfg(not(feature = "heap_debug"))]
+    #[test_case]
+    fn realloc_within_size_class_keeps_object() {
+        let layout = Layout::from_size_align(20, 8).unwrap();
+        let p1 = unsafe { KERNEL_HEAP.alloc(layout) };
+        unsafe { p1.write_bytes(0x3c, layout.size()) };
+        let p2 = unsafe { KERNEL_HEAP.realloc(p1, layout, 32) };
+        assert_eq!(p2, p1);
+        let p3 = unsafe { KERNEL_HEAP.realloc(p2, Layout::from_size_align(32, 8).unwrap(), 100) };
+        assert_ne!(p3, p2);
+        assert_eq!(unsafe { *p3.add(layout.size() - 1) }, 0x3c);
+        unsafe { KERNEL_HEAP.dealloc(p3, Layout::from_size_align(100, 8).unwrap()) };
+    }
+
+    #[cfg(not(feature = "heap_debug"))]
+    #[test_case]
+    fn small_objects_are_aligned_and_distinct() {
+        const NUM_OBJECTS: usize = 500;
+        let mut allocated = Vec::with_capacity(NUM_OBJECTS * 8);
+        let before = KERNEL_HEAP.stats();
+        for i in 0..NUM_OBJECTS {
+            for shift in 4..12 {
+                let size = 1 << shift;
+                let layout = Layout::from_size_align(size - i % 8, 8).unwrap();
+                let p = unsafe { KERNEL_HEAP.alloc(layout) };
+                assert!(!p.is_null());
+                assert_eq!(p as usize % 8, 0);
+                unsafe { p.write_bytes(i as u8, layout.size()) };
+                allocated.push((p, layout));
+            }
+        }
+        for (i, (p, layout)) in allocated.iter().enumerate() {
+            let expected = (i / 8) as u8;
+            assert_eq!(unsafe { **p }, expected);
+            assert_eq!(unsafe { *p.add(layout.size() - 1) }, expected);
+        }
+        for (p, layout) in allocated.drain(..) {
+            unsafe { KERNEL_HEAP.dealloc(p, layout) };
+        }
+        let after = KERNEL_HEAP.stats();
+        assert_eq!(after.objects_in_use, before.objects_in_use);
+        // 空のスラブはサイズクラスごとに高々1枚しか残らない
+        assert!(after.slab_count <= before.slab_count + 8);
+    }
+
+    // 多数の生存オブジェクトがある状態で、解放と確保を1回ずつ行うのにかかるサイクル数
+    #[cfg(not(feature = "heap_debug"))]
+    fn bench<A: GlobalAlloc>(allocator: &A) -> u64 {
+        const NUM_LIVE_OBJECTS: usize = 512;
+        const NUM_ITERATIONS: usize = 4096;
+        let layout = Layout::from_size_align(48, 8).unwrap();
+        let mut live = Vec::with_capacity(NUM_LIVE_OBJECTS);
+        for _ in 0..NUM_LIVE_OBJECTS {
+            live.push(unsafe { allocator.alloc(layout) });
+        }
+        let start = rdtsc();
+        for i in 0..NUM_ITERATIONS {
+            let j = i % NUM_LIVE_OBJECTS;
+            unsafe {
+                allocator.dealloc(live[j], layout);
+                live[j] = allocator.alloc(layout);
+            }
+        }
+        let cycles = rdtsc() - start;
+        for p in live {
+            unsafe { allocator.dealloc(p, layout) };
+        }
+        cycles / NUM_ITERATIONS as u64
+    }
+
+    // スラブとバックエンドの速さを、同じ確保と解放の繰り返しで比べてシリアルに出す
+    #[cfg(not(feature = "heap_debug"))]
+    #[test_case]
+    fn bench_slab_vs_backing() {
+        let backing = bench(KERNEL_HEAP.backing());
+        let slab = bench(&KERNEL_HEAP);
+        let mut w = SerialPort::new(COM1);
+        writeln!(w).unwrap();
+        writeln!(w, "  backing: {backing} cycles per dealloc+alloc").unwrap();
+        writeln!(w, "  slab:    {slab} cycles per dealloc+alloc").unwrap();
+    }
+
+    // 生存オブジェクトが多い状態で解放と確保を繰り返しても、スラブ内のオブジェクトを使い回し、
+    // バックエンドには新たな確保を求めない
+    #[cfg(not(feature = "heap_debug"))]
+    #[test_case]
+    fn churn_reuses_slab_objects() {
+        const NUM_LIVE_OBJECTS: usize = 512;
+        const NUM_ITERATIONS: usize = 4096;
+        let layout = Layout::from_size_align(48, 8).unwrap();
+        let mut live = Vec::with_capacity(NUM_LIVE_OBJECTS);
+        for _ in 0..NUM_LIVE_OBJECTS {
+            live.push(unsafe { KERNEL_HEAP.alloc(layout) });
+        }
+        let before = KERNEL_HEAP.stats();
+        let backing_allocations = KERNEL_HEAP.backing().stats().allocation_count;
+        for i in 0..NUM_ITERATIONS {
+            let j = i % NUM_LIVE_OBJECTS;
+            unsafe {
+                KERNEL_HEAP.dealloc(live[j], layout);
+                live[j] = KERNEL_HEAP.alloc(layout);
+            }
+            assert!(!live[j].is_null());
+        }
+        let after = KERNEL_HEAP.stats();
+        assert_eq!(after.slab_count, before.slab_count);
+        assert_eq!(after.objects_in_use, before.objects_in_use);
+        assert_eq!(
+            KERNEL_HEAP.backing().stats().allocation_count,
+            backing_allocations
+        );
+        for p in live {
+            unsafe { KERNEL_HEAP.dealloc(p, layout) };
+        }
+        assert_eq!(
+            KERNEL_HEAP.stats().objects_in_use,
+            before.objects_in_use - NUM_LIVE_OBJECTS
+        );
+    }
+
+    // heap_debugフィーチャでは、小さなオブジェクトもレッドゾーンで検査される
+    #[cfg(all(feature = "heap_debug", not(feature = "buddy_allocator")))]
+    #[test_case]
+    fn small_objects_are_checked_with_heap_debug() {
+        let layout = Layout::from_size_align(32, 8).unwrap();
+        let errors = KERNEL_HEAP.backing().debug_error_count();
+        let p = unsafe { KERNEL_HEAP.alloc(layout) };
+        assert!(!p.is_null());
+        unsafe {
+            p.add(layout.size()).write(0);
+            KERNEL_HEAP.dealloc(p, layout);
+        }
+        assert_eq!(KERNEL_HEAP.backing().debug_error_count(), errors + 1);
+        let p = unsafe { KERNEL_HEAP.alloc(layout) };
+        unsafe {
+            KERNEL_HEAP.dealloc(p, layout);
+            KERNEL_HEAP.dealloc(p, layout);
+        }
+        assert_eq!(KERNEL_HEAP.backing().debug_error_count(), errors + 2);
+        assert!(KERNEL_HEAP.backing().validate().is_ok());
+    }
+}
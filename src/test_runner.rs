@@ -1,12 +1,62 @@
+use crate::panic::write_panic_info;
 use crate::qemu::exit_qemu;
 use crate::qemu::QemuExitCode;
-use::core::panic::PanicInfo;
+use crate::x86::serial::SerialPort;
+use crate::x86::serial::COM1;
+use core::any::type_name;
+use core::fmt::Write;
+use core::panic::PanicInfo;
+use core::sync::atomic::AtomicUsize;
+use core::sync::atomic::Ordering;
 
-pub fn test_runner(_tests: &[&dyn FnOnce()]) -> ! {
+static TOTAL: AtomicUsize = AtomicUsize::new(0);
+static PASSED: AtomicUsize = AtomicUsize::new(0);
+
+pub trait Testable {
+    fn run(&self);
+}
+
+impl<T: Fn()> Testable for T {
+    fn run(&self) {
+        let mut w = SerialPort::new(COM1);
+        write!(w, "{}...\t", type_name::<T>()).unwrap();
+        self();
+        writeln!(w, "[PASS]").unwrap();
+        PASSED.fetch_add(1, Ordering::SeqCst);
+    }
+}
+
+fn write_summary(w: &mut SerialPort, failed: usize) {
+    let total = TOTAL.load(Ordering::SeqCst);
+    let passed = PASSED.load(Ordering::SeqCst);
+    writeln!(
+        w,
+        "test result: {passed} passed; {failed} failed; {} not run",
+        total - passed - failed
+    )
+    .unwrap();
+}
+
+pub fn test_runner(tests: &[&dyn Testable]) -> ! {
+    let mut w = SerialPort::new(COM1);
+    w.init(115200).expect("Failed to initialize serial port");
+    TOTAL.store(tests.len(), Ordering::SeqCst);
+    writeln!(w, "Running {} tests", tests.len()).unwrap();
+    for test in tests {
+        test.run();
+    }
+    write_summary(&mut w, 0);
     exit_qemu(QemuExitCode::Success);
 }
 
+// panic = "abort" なので、テストの失敗はここに来る
 #[panic_handler]
-fn panic(_info: &PanicInfo) -> ! {
+fn panic(info: &PanicInfo) -> ! {
+    let mut w = SerialPort::new(COM1);
+    writeln!(w, "[FAIL]").unwrap();
+    let _ = write_panic_info(&mut w, info);
+    if TOTAL.load(Ordering::SeqCst) != 0 {
+        write_summary(&mut w, 1);
+    }
     exit_qemu(QemuExitCode::Failure);
-}
\ No newline at end of file
+}
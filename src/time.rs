@@ -0,0 +1,187 @@
+use crate::result::Error;
+use crate::result::Result;
+use crate::x86::timer;
+use core::fmt;
+use core::sync::atomic::AtomicBool;
+use core::sync::atomic::AtomicU64;
+use core::sync::atomic::Ordering;
+
+const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+// 0000-03-01から1970-01-01までの日数
+const DAYS_FROM_MARCH_EPOCH_TO_UNIX_EPOCH: i64 = 719468;
+const DAYS_PER_400_YEARS: i64 = 146097;
+
+// UTCの日時 (秒単位)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct DateTime {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
+
+fn is_leap_year(year: u16) -> bool {
+    (year % 4 == 0 && year % 100 != 0) || year % 400 == 0
+}
+
+fn days_in_month(year: u16, month: u8) -> u8 {
+    match month {
+        2 if is_leap_year(year) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+// 年を3月始まりで数えると、うるう日が年の最後に来るので計算が簡単になる
+fn days_from_civil(year: u16, month: u8, day: u8) -> i64 {
+    let year = year as i64 - (month <= 2) as i64;
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let month_from_march = (month as i64 + 9) % 12;
+    let day_of_year = (153 * month_from_march + 2) / 5 + day as i64 - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * DAYS_PER_400_YEARS + day_of_era - DAYS_FROM_MARCH_EPOCH_TO_UNIX_EPOCH
+}
+
+fn civil_from_days(days: i64) -> (u16, u8, u8) {
+    let days = days + DAYS_FROM_MARCH_EPOCH_TO_UNIX_EPOCH;
+    let era = days.div_euclid(DAYS_PER_400_YEARS);
+    let day_of_era = days - era * DAYS_PER_400_YEARS;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_from_march = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_from_march + 2) / 5 + 1;
+    let month = if month_from_march < 10 {
+        month_from_march + 3
+    } else {
+        month_from_march - 9
+    };
+    let year = year_of_era + era * 400 + (month <= 2) as i64;
+    (year as u16, month as u8, day as u8)
+}
+
+impl DateTime {
+    pub fn new(year: u16, month: u8, day: u8, hour: u8, minute: u8, second: u8) -> Result<Self> {
+        if year < 1970
+            || !(1..=12).contains(&month)
+            || day == 0
+            || day > days_in_month(year, month)
+            || hour >= 24
+            || minute >= 60
+            || second >= 60
+        {
+            return Err(Error::InvalidParameter);
+        }
+        Ok(Self {
+            year,
+            month,
+            day,
+            hour,
+            minute,
+            second,
+        })
+    }
+
+    pub fn from_unix_timestamp(timestamp: u64) -> Self {
+        let (year, month, day) = civil_from_days((timestamp / SECONDS_PER_DAY) as i64);
+        let seconds_of_day = timestamp % SECONDS_PER_DAY;
+        Self {
+            year,
+            month,
+            day,
+            hour: (seconds_of_day / 3600) as u8,
+            minute: (seconds_of_day / 60 % 60) as u8,
+            second: (seconds_of_day % 60) as u8,
+        }
+    }
+
+    // 1970-01-01T00:00:00Zからの秒数
+    pub fn unix_timestamp(&self) -> u64 {
+        let days = days_from_civil(self.year, self.month, self.day) as u64;
+        days * SECONDS_PER_DAY
+            + self.hour as u64 * 3600
+            + self.minute as u64 * 60
+            + self.second as u64
+    }
+}
+
+// "2024-01-01T12:34:56Z" の形式で出力する
+impl fmt::Display for DateTime {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+            self.year, self.month, self.day, self.hour, self.minute, self.second
+        )
+    }
+}
+
+// 壁時計は、ある時点の日時と、そのときの単調増加の時刻の組で表す
+static WALL_CLOCK_IS_SET: AtomicBool = AtomicBool::new(false);
+static WALL_CLOCK_BASE_TIMESTAMP: AtomicU64 = AtomicU64::new(0);
+static WALL_CLOCK_BASE_NANOS: AtomicU64 = AtomicU64::new(0);
+
+pub fn set_wall_clock(now: DateTime) {
+    WALL_CLOCK_BASE_TIMESTAMP.store(now.unix_timestamp(), Ordering::SeqCst);
+    WALL_CLOCK_BASE_NANOS.store(timer::now().as_nanos() as u64, Ordering::SeqCst);
+    WALL_CLOCK_IS_SET.store(true, Ordering::SeqCst);
+}
+
+// 現在のUTCの日時。壁時計が設定されていなければNone
+pub fn now_utc() -> Option<DateTime> {
+    if !WALL_CLOCK_IS_SET.load(Ordering::SeqCst) {
+        return None;
+    }
+    let elapsed_nanos =
+        timer::now().as_nanos() as u64 - WALL_CLOCK_BASE_NANOS.load(Ordering::SeqCst);
+    let timestamp = WALL_CLOCK_BASE_TIMESTAMP.load(Ordering::SeqCst) + elapsed_nanos / 1_000_000_000;
+    Some(DateTime::from_unix_timestamp(timestamp))
+}
+
+#[cfg(test)]
+mod test {
+    use super::now_utc;
+    use super::DateTime;
+    use crate::result::Error;
+    use alloc::format;
+
+    #[test_case]
+    fn unix_timestamp_round_trips() {
+        let cases = [
+            (DateTime::new(1970, 1, 1, 0, 0, 0).unwrap(), 0),
+            (DateTime::new(2000, 2, 29, 23, 59, 59).unwrap(), 951868799),
+            (DateTime::new(2024, 2, 29, 12, 0, 0).unwrap(), 1709208000),
+            (DateTime::new(2038, 1, 19, 3, 14, 8).unwrap(), 1 << 31),
+        ];
+        for (date_time, timestamp) in cases {
+            assert_eq!(date_time.unix_timestamp(), timestamp);
+            assert_eq!(DateTime::from_unix_timestamp(timestamp), date_time);
+        }
+    }
+
+    #[test_case]
+    fn invalid_dates_are_rejected() {
+        assert_eq!(DateTime::new(2023, 2, 29, 0, 0, 0), Err(Error::InvalidParameter));
+        assert_eq!(DateTime::new(2024, 13, 1, 0, 0, 0), Err(Error::InvalidParameter));
+        assert_eq!(DateTime::new(2024, 4, 31, 0, 0, 0), Err(Error::InvalidParameter));
+        assert_eq!(DateTime::new(2024, 1, 1, 24, 0, 0), Err(Error::InvalidParameter));
+        assert!(DateTime::new(2100, 2, 28, 23, 59, 59).is_ok());
+        assert!(DateTime::new(2100, 2, 29, 0, 0, 0).is_err());
+    }
+
+    #[test_case]
+    fn date_time_is_formatted_as_iso8601() {
+        let date_time = DateTime::new(2024, 1, 2, 3, 4, 5).unwrap();
+        assert_eq!(format!("{date_time}"), "2024-01-02T03:04:05Z");
+    }
+
+    #[test_case]
+    fn wall_clock_is_set_at_boot() {
+        let now = now_utc().expect("Wall clock is not set");
+        assert!(now.year >= 2024);
+    }
+}
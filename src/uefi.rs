@@ -1,7 +1,11 @@
+use core::cmp::max;
 use core::fmt;
+use core::ops::BitOr;
 use crate::graphics::draw_font_fg;
 use crate::graphics::Bitmap;
+use crate::result::Error;
 use crate::result::Result;
+use crate::time::DateTime;
 use core::mem::size_of;
 use core::ptr::null_mut;
 
@@ -24,34 +28,345 @@ const EFI_GRAPHGICS_OUTPUT_PROTOCOL_GUID: EfiGuid = EfiGuid {
     data3: [0x96, 0xfb, 0x7a, 0xde, 0xd0, 0x80, 0x51, 0x6a],
 };
 
-#[derive(Debug, PartialEq, Eq,Clone, Copy)]
+const EFI_ACPI_20_TABLE_GUID: EfiGuid = EfiGuid {
+    data0: 0x8868e871,
+    data1: 0xe4f1,
+    data2: 0x11d3,
+    data3: [0xbc, 0x22, 0x00, 0x80, 0xc7, 0x3c, 0x88, 0x81],
+};
+
+const EFI_STATUS_ERROR_BIT: u64 = 1 << 63;
+
+// UEFI仕様のEFI_STATUS。ファームウェアは未知の値も返しうるので、enumではなくu64のnewtypeにする
+#[derive(PartialEq, Eq, Clone, Copy)]
 #[must_use]
-#[repr(u64)]
-pub enum EfiStatus {
-    Success = 0,
+#[repr(transparent)]
+pub struct EfiStatus(u64);
+
+impl EfiStatus {
+    pub const SUCCESS: EfiStatus = EfiStatus(0);
+
+    // エラー (最上位ビットが立っている)
+    pub const LOAD_ERROR: EfiStatus = EfiStatus::error(1);
+    pub const INVALID_PARAMETER: EfiStatus = EfiStatus::error(2);
+    pub const UNSUPPORTED: EfiStatus = EfiStatus::error(3);
+    pub const BAD_BUFFER_SIZE: EfiStatus = EfiStatus::error(4);
+    pub const BUFFER_TOO_SMALL: EfiStatus = EfiStatus::error(5);
+    pub const NOT_READY: EfiStatus = EfiStatus::error(6);
+    pub const DEVICE_ERROR: EfiStatus = EfiStatus::error(7);
+    pub const WRITE_PROTECTED: EfiStatus = EfiStatus::error(8);
+    pub const OUT_OF_RESOURCES: EfiStatus = EfiStatus::error(9);
+    pub const VOLUME_CORRUPTED: EfiStatus = EfiStatus::error(10);
+    pub const VOLUME_FULL: EfiStatus = EfiStatus::error(11);
+    pub const NO_MEDIA: EfiStatus = EfiStatus::error(12);
+    pub const MEDIA_CHANGED: EfiStatus = EfiStatus::error(13);
+    pub const NOT_FOUND: EfiStatus = EfiStatus::error(14);
+    pub const ACCESS_DENIED: EfiStatus = EfiStatus::error(15);
+    pub const NO_RESPONSE: EfiStatus = EfiStatus::error(16);
+    pub const NO_MAPPING: EfiStatus = EfiStatus::error(17);
+    pub const TIMEOUT: EfiStatus = EfiStatus::error(18);
+    pub const NOT_STARTED: EfiStatus = EfiStatus::error(19);
+    pub const ALREADY_STARTED: EfiStatus = EfiStatus::error(20);
+    pub const ABORTED: EfiStatus = EfiStatus::error(21);
+    pub const ICMP_ERROR: EfiStatus = EfiStatus::error(22);
+    pub const TFTP_ERROR: EfiStatus = EfiStatus::error(23);
+    pub const PROTOCOL_ERROR: EfiStatus = EfiStatus::error(24);
+    pub const INCOMPATIBLE_VERSION: EfiStatus = EfiStatus::error(25);
+    pub const SECURITY_VIOLATION: EfiStatus = EfiStatus::error(26);
+    pub const CRC_ERROR: EfiStatus = EfiStatus::error(27);
+    pub const END_OF_MEDIA: EfiStatus = EfiStatus::error(28);
+    pub const END_OF_FILE: EfiStatus = EfiStatus::error(31);
+    pub const INVALID_LANGUAGE: EfiStatus = EfiStatus::error(32);
+    pub const COMPROMISED_DATA: EfiStatus = EfiStatus::error(33);
+    pub const IP_ADDRESS_CONFLICT: EfiStatus = EfiStatus::error(34);
+    pub const HTTP_ERROR: EfiStatus = EfiStatus::error(35);
+
+    // 警告 (最上位ビットが立っていない)
+    pub const WARN_UNKNOWN_GLYPH: EfiStatus = EfiStatus(1);
+    pub const WARN_DELETE_FAILURE: EfiStatus = EfiStatus(2);
+    pub const WARN_WRITE_FAILURE: EfiStatus = EfiStatus(3);
+    pub const WARN_BUFFER_TOO_SMALL: EfiStatus = EfiStatus(4);
+    pub const WARN_STALE_DATA: EfiStatus = EfiStatus(5);
+    pub const WARN_FILE_SYSTEM: EfiStatus = EfiStatus(6);
+    pub const WARN_RESET_REQUIRED: EfiStatus = EfiStatus(7);
+
+    const fn error(code: u64) -> EfiStatus {
+        EfiStatus(EFI_STATUS_ERROR_BIT | code)
+    }
+
+    pub const fn from_raw(value: u64) -> EfiStatus {
+        EfiStatus(value)
+    }
+
+    pub const fn value(&self) -> u64 {
+        self.0
+    }
+
+    pub const fn is_success(&self) -> bool {
+        self.0 == 0
+    }
+
+    pub const fn is_error(&self) -> bool {
+        self.0 & EFI_STATUS_ERROR_BIT != 0
+    }
+
+    pub const fn is_warning(&self) -> bool {
+        !self.is_success() && !self.is_error()
+    }
+
+    pub fn name(&self) -> Option<&'static str> {
+        Some(match *self {
+            EfiStatus::SUCCESS => "EFI_SUCCESS",
+            EfiStatus::LOAD_ERROR => "EFI_LOAD_ERROR",
+            EfiStatus::INVALID_PARAMETER => "EFI_INVALID_PARAMETER",
+            EfiStatus::UNSUPPORTED => "EFI_UNSUPPORTED",
+            EfiStatus::BAD_BUFFER_SIZE => "EFI_BAD_BUFFER_SIZE",
+            EfiStatus::BUFFER_TOO_SMALL => "EFI_BUFFER_TOO_SMALL",
+            EfiStatus::NOT_READY => "EFI_NOT_READY",
+            EfiStatus::DEVICE_ERROR => "EFI_DEVICE_ERROR",
+            EfiStatus::WRITE_PROTECTED => "EFI_WRITE_PROTECTED",
+            EfiStatus::OUT_OF_RESOURCES => "EFI_OUT_OF_RESOURCES",
+            EfiStatus::VOLUME_CORRUPTED => "EFI_VOLUME_CORRUPTED",
+            EfiStatus::VOLUME_FULL => "EFI_VOLUME_FULL",
+            EfiStatus::NO_MEDIA => "EFI_NO_MEDIA",
+            EfiStatus::MEDIA_CHANGED => "EFI_MEDIA_CHANGED",
+            EfiStatus::NOT_FOUND => "EFI_NOT_FOUND",
+            EfiStatus::ACCESS_DENIED => "EFI_ACCESS_DENIED",
+            EfiStatus::NO_RESPONSE => "EFI_NO_RESPONSE",
+            EfiStatus::NO_MAPPING => "EFI_NO_MAPPING",
+            EfiStatus::TIMEOUT => "EFI_TIMEOUT",
+            EfiStatus::NOT_STARTED => "EFI_NOT_STARTED",
+            EfiStatus::ALREADY_STARTED => "EFI_ALREADY_STARTED",
+            EfiStatus::ABORTED => "EFI_ABORTED",
+            EfiStatus::ICMP_ERROR => "EFI_ICMP_ERROR",
+            EfiStatus::TFTP_ERROR => "EFI_TFTP_ERROR",
+            EfiStatus::PROTOCOL_ERROR => "EFI_PROTOCOL_ERROR",
+            EfiStatus::INCOMPATIBLE_VERSION => "EFI_INCOMPATIBLE_VERSION",
+            EfiStatus::SECURITY_VIOLATION => "EFI_SECURITY_VIOLATION",
+            EfiStatus::CRC_ERROR => "EFI_CRC_ERROR",
+            EfiStatus::END_OF_MEDIA => "EFI_END_OF_MEDIA",
+            EfiStatus::END_OF_FILE => "EFI_END_OF_FILE",
+            EfiStatus::INVALID_LANGUAGE => "EFI_INVALID_LANGUAGE",
+            EfiStatus::COMPROMISED_DATA => "EFI_COMPROMISED_DATA",
+            EfiStatus::IP_ADDRESS_CONFLICT => "EFI_IP_ADDRESS_CONFLICT",
+            EfiStatus::HTTP_ERROR => "EFI_HTTP_ERROR",
+            EfiStatus::WARN_UNKNOWN_GLYPH => "EFI_WARN_UNKNOWN_GLYPH",
+            EfiStatus::WARN_DELETE_FAILURE => "EFI_WARN_DELETE_FAILURE",
+            EfiStatus::WARN_WRITE_FAILURE => "EFI_WARN_WRITE_FAILURE",
+            EfiStatus::WARN_BUFFER_TOO_SMALL => "EFI_WARN_BUFFER_TOO_SMALL",
+            EfiStatus::WARN_STALE_DATA => "EFI_WARN_STALE_DATA",
+            EfiStatus::WARN_FILE_SYSTEM => "EFI_WARN_FILE_SYSTEM",
+            EfiStatus::WARN_RESET_REQUIRED => "EFI_WARN_RESET_REQUIRED",
+            _ => return None,
+        })
+    }
+
+    // 警告は成功として扱う
+    pub fn into_result(self) -> Result<()> {
+        if self.is_error() {
+            Err(Error::Efi(self))
+        } else {
+            Ok(())
+        }
+    }
 }
 
-#[repr(i64)]
-#[derive(Clone, Copy, PartialEq, Eq, Debug)]
-#[allow(non_camel_case_types)]
-pub enum EfiMemoryType {
-    RESERVED = 0,
-    LOADER_CODE,
-    LOADER_DATA,
-    BOOT_SERVICES_CODE,
-    BOOT_SERVICES_DATA,
-    RUNTIME_SERVICES_CODE,
-    RUNTIME_SERVICES_DATA,
-    CONVENTIONAL_MEMORY,
-    UNUSABLE_MEMORY,
-    ACPI_RECLAIM_MEMORY,
-    ACPI_NVS_MEMORY,
-    MEMORY_MAPPED_IO,
-    MEMORY_MAPPED_IO_PORT_SPACE,
-    PAL_CODE,
-    PERSISTENT_MEMORY,
+impl fmt::Debug for EfiStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.name() {
+            Some(name) => write!(f, "{name}"),
+            None => write!(f, "EfiStatus({:#018X})", self.0),
+        }
+    }
+}
+
+impl fmt::Display for EfiStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(self, f)
+    }
+}
+
+impl From<EfiStatus> for Result<()> {
+    fn from(status: EfiStatus) -> Self {
+        status.into_result()
+    }
+}
+
+// EFI_MEMORY_DESCRIPTORのTypeはUINT32で、その後に4バイトのパディングが続く。
+// ファームウェアは仕様にない値やOEM、OS向けの値も返しうるので、enumではなくu32のnewtypeにする
+#[derive(Clone, Copy, PartialEq, Eq)]
+#[repr(transparent)]
+pub struct EfiMemoryType(u32);
+
+impl EfiMemoryType {
+    pub const RESERVED: EfiMemoryType = EfiMemoryType(0);
+    pub const LOADER_CODE: EfiMemoryType = EfiMemoryType(1);
+    pub const LOADER_DATA: EfiMemoryType = EfiMemoryType(2);
+    pub const BOOT_SERVICES_CODE: EfiMemoryType = EfiMemoryType(3);
+    pub const BOOT_SERVICES_DATA: EfiMemoryType = EfiMemoryType(4);
+    pub const RUNTIME_SERVICES_CODE: EfiMemoryType = EfiMemoryType(5);
+    pub const RUNTIME_SERVICES_DATA: EfiMemoryType = EfiMemoryType(6);
+    pub const CONVENTIONAL_MEMORY: EfiMemoryType = EfiMemoryType(7);
+    pub const UNUSABLE_MEMORY: EfiMemoryType = EfiMemoryType(8);
+    pub const ACPI_RECLAIM_MEMORY: EfiMemoryType = EfiMemoryType(9);
+    pub const ACPI_NVS_MEMORY: EfiMemoryType = EfiMemoryType(10);
+    pub const MEMORY_MAPPED_IO: EfiMemoryType = EfiMemoryType(11);
+    pub const MEMORY_MAPPED_IO_PORT_SPACE: EfiMemoryType = EfiMemoryType(12);
+    pub const PAL_CODE: EfiMemoryType = EfiMemoryType(13);
+    pub const PERSISTENT_MEMORY: EfiMemoryType = EfiMemoryType(14);
+    pub const UNACCEPTED_MEMORY: EfiMemoryType = EfiMemoryType(15);
+    // この範囲の値は、OEMやOSが独自の意味で使う
+    const OEM_RESERVED_MIN: u32 = 0x7000_0000;
+    const OS_RESERVED_MIN: u32 = 0x8000_0000;
+
+    pub const ALL: [EfiMemoryType; 16] = [
+        EfiMemoryType::RESERVED,
+        EfiMemoryType::LOADER_CODE,
+        EfiMemoryType::LOADER_DATA,
+        EfiMemoryType::BOOT_SERVICES_CODE,
+        EfiMemoryType::BOOT_SERVICES_DATA,
+        EfiMemoryType::RUNTIME_SERVICES_CODE,
+        EfiMemoryType::RUNTIME_SERVICES_DATA,
+        EfiMemoryType::CONVENTIONAL_MEMORY,
+        EfiMemoryType::UNUSABLE_MEMORY,
+        EfiMemoryType::ACPI_RECLAIM_MEMORY,
+        EfiMemoryType::ACPI_NVS_MEMORY,
+        EfiMemoryType::MEMORY_MAPPED_IO,
+        EfiMemoryType::MEMORY_MAPPED_IO_PORT_SPACE,
+        EfiMemoryType::PAL_CODE,
+        EfiMemoryType::PERSISTENT_MEMORY,
+        EfiMemoryType::UNACCEPTED_MEMORY,
+    ];
+
+    pub const fn value(&self) -> u32 {
+        self.0
+    }
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            EfiMemoryType::RESERVED => "RESERVED",
+            EfiMemoryType::LOADER_CODE => "LOADER_CODE",
+            EfiMemoryType::LOADER_DATA => "LOADER_DATA",
+            EfiMemoryType::BOOT_SERVICES_CODE => "BOOT_SERVICES_CODE",
+            EfiMemoryType::BOOT_SERVICES_DATA => "BOOT_SERVICES_DATA",
+            EfiMemoryType::RUNTIME_SERVICES_CODE => "RUNTIME_SERVICES_CODE",
+            EfiMemoryType::RUNTIME_SERVICES_DATA => "RUNTIME_SERVICES_DATA",
+            EfiMemoryType::CONVENTIONAL_MEMORY => "CONVENTIONAL_MEMORY",
+            EfiMemoryType::UNUSABLE_MEMORY => "UNUSABLE_MEMORY",
+            EfiMemoryType::ACPI_RECLAIM_MEMORY => "ACPI_RECLAIM_MEMORY",
+            EfiMemoryType::ACPI_NVS_MEMORY => "ACPI_NVS_MEMORY",
+            EfiMemoryType::MEMORY_MAPPED_IO => "MEMORY_MAPPED_IO",
+            EfiMemoryType::MEMORY_MAPPED_IO_PORT_SPACE => "MEMORY_MAPPED_IO_PORT_SPACE",
+            EfiMemoryType::PAL_CODE => "PAL_CODE",
+            EfiMemoryType::PERSISTENT_MEMORY => "PERSISTENT_MEMORY",
+            EfiMemoryType::UNACCEPTED_MEMORY => "UNACCEPTED_MEMORY",
+            EfiMemoryType(v) if v >= Self::OS_RESERVED_MIN => "OS_RESERVED",
+            EfiMemoryType(v) if v >= Self::OEM_RESERVED_MIN => "OEM_RESERVED",
+            _ => "UNKNOWN",
+        }
+    }
+}
+
+impl fmt::Debug for EfiMemoryType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}({:#X})", self.name(), self.0)
+    }
+}
+
+impl fmt::Display for EfiMemoryType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.pad(self.name())
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Default)]
+#[repr(transparent)]
+pub struct EfiMemoryAttribute(u64);
+
+impl EfiMemoryAttribute {
+    pub const UC: EfiMemoryAttribute = EfiMemoryAttribute(0x1);
+    pub const WC: EfiMemoryAttribute = EfiMemoryAttribute(0x2);
+    pub const WT: EfiMemoryAttribute = EfiMemoryAttribute(0x4);
+    pub const WB: EfiMemoryAttribute = EfiMemoryAttribute(0x8);
+    pub const UCE: EfiMemoryAttribute = EfiMemoryAttribute(0x10);
+    pub const WP: EfiMemoryAttribute = EfiMemoryAttribute(0x1000);
+    pub const RP: EfiMemoryAttribute = EfiMemoryAttribute(0x2000);
+    pub const XP: EfiMemoryAttribute = EfiMemoryAttribute(0x4000);
+    pub const NV: EfiMemoryAttribute = EfiMemoryAttribute(0x8000);
+    pub const MORE_RELIABLE: EfiMemoryAttribute = EfiMemoryAttribute(0x10000);
+    pub const RO: EfiMemoryAttribute = EfiMemoryAttribute(0x20000);
+    pub const SP: EfiMemoryAttribute = EfiMemoryAttribute(0x40000);
+    pub const CPU_CRYPTO: EfiMemoryAttribute = EfiMemoryAttribute(0x80000);
+    pub const RUNTIME: EfiMemoryAttribute = EfiMemoryAttribute(1 << 63);
+
+    const NAMES: [(EfiMemoryAttribute, &'static str); 14] = [
+        (EfiMemoryAttribute::UC, "UC"),
+        (EfiMemoryAttribute::WC, "WC"),
+        (EfiMemoryAttribute::WT, "WT"),
+        (EfiMemoryAttribute::WB, "WB"),
+        (EfiMemoryAttribute::UCE, "UCE"),
+        (EfiMemoryAttribute::WP, "WP"),
+        (EfiMemoryAttribute::RP, "RP"),
+        (EfiMemoryAttribute::XP, "XP"),
+        (EfiMemoryAttribute::NV, "NV"),
+        (EfiMemoryAttribute::MORE_RELIABLE, "MORE_RELIABLE"),
+        (EfiMemoryAttribute::RO, "RO"),
+        (EfiMemoryAttribute::SP, "SP"),
+        (EfiMemoryAttribute::CPU_CRYPTO, "CPU_CRYPTO"),
+        (EfiMemoryAttribute::RUNTIME, "RUNTIME"),
+    ];
+
+    pub const fn bits(&self) -> u64 {
+        self.0
+    }
+
+    pub const fn contains(&self, other: EfiMemoryAttribute) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl BitOr for EfiMemoryAttribute {
+    type Output = EfiMemoryAttribute;
+    fn bitor(self, rhs: EfiMemoryAttribute) -> EfiMemoryAttribute {
+        EfiMemoryAttribute(self.0 | rhs.0)
+    }
 }
 
+// "UC|WC|WT|WB|RUNTIME" のように出力する
+impl fmt::Display for EfiMemoryAttribute {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut rest = self.0;
+        let mut first = true;
+        for (attr, name) in EfiMemoryAttribute::NAMES.iter() {
+            if self.contains(*attr) {
+                if !first {
+                    write!(f, "|")?;
+                }
+                write!(f, "{name}")?;
+                first = false;
+                rest &= !attr.0;
+            }
+        }
+        if rest != 0 {
+            if !first {
+                write!(f, "|")?;
+            }
+            write!(f, "{rest:#X}")?;
+        } else if first {
+            write!(f, "-")?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Debug for EfiMemoryAttribute {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "EfiMemoryAttribute({self})")
+    }
+}
+
+pub const EFI_PAGE_SIZE: u64 = 4096;
+
 #[repr(C)]
 #[derive(Clone, Copy, PartialEq, Eq, Debug)]
 pub struct MemoryDescriptor {
@@ -59,7 +374,7 @@ pub struct MemoryDescriptor {
     physical_start: u64,
     virtual_start: u64,
     number_of_pages: u64,
-    attribute: u64,
+    attribute: EfiMemoryAttribute,
 }
 
 impl MemoryDescriptor {
@@ -74,12 +389,48 @@ impl MemoryDescriptor {
     pub fn physical_start(&self) -> u64 {
         self.physical_start
     }
+
+    pub fn virtual_start(&self) -> u64 {
+        self.virtual_start
+    }
+
+    pub fn attribute(&self) -> EfiMemoryAttribute {
+        self.attribute
+    }
+
+    pub fn size(&self) -> u64 {
+        self.number_of_pages * EFI_PAGE_SIZE
+    }
+
+    // 領域の終端 (この番地は含まない)
+    pub fn physical_end(&self) -> u64 {
+        self.physical_start + self.size()
+    }
+}
+
+impl fmt::Display for MemoryDescriptor {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:<27} {:#018X}-{:#018X} {:>8} {}",
+            self.memory_type,
+            self.physical_start,
+            self.physical_end(),
+            self.number_of_pages,
+            self.attribute
+        )
+    }
 }
 
-const MEMORY_MAP_BUFFER_SIZE: usize = 0x8000;
+const EFI_MEMORY_DESCRIPTOR_VERSION: u32 = 1;
+// AllocatePool自体がメモリマップを分割して記述子を増やすので、その分の余裕を持たせる
+const MEMORY_MAP_SLACK_DESCRIPTORS: usize = 8;
+// ExitBootServicesに失敗した後は確保し直せないので、取得した後もこれだけの余裕を残しておく
+const MEMORY_MAP_MIN_SLACK_DESCRIPTORS: usize = 4;
 
 pub struct MemoryMapHolder {
-    buffer: [u8; MEMORY_MAP_BUFFER_SIZE],
+    buffer: *mut u8,
+    buffer_size: usize,
     size: usize,
     map_key: usize,
     descriptor_size: usize,
@@ -89,8 +440,9 @@ pub struct MemoryMapHolder {
 impl MemoryMapHolder {
     pub const fn new() -> MemoryMapHolder {
         MemoryMapHolder {
-            buffer: [0; MEMORY_MAP_BUFFER_SIZE],
-            size: MEMORY_MAP_BUFFER_SIZE,
+            buffer: null_mut(),
+            buffer_size: 0,
+            size: 0,
             map_key: 0,
             descriptor_size: 0,
             descriptor_version: 0,
@@ -103,6 +455,64 @@ impl MemoryMapHolder {
             ofs: 0,
         }
     }
+
+    pub fn total_pages(&self, memory_type: EfiMemoryType) -> u64 {
+        self.iter()
+            .filter(|e| e.memory_type() == memory_type)
+            .map(|e| e.number_of_pages())
+            .sum()
+    }
+
+    pub fn descriptor_size(&self) -> usize {
+        self.descriptor_size
+    }
+
+    pub fn descriptor_version(&self) -> u32 {
+        self.descriptor_version
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        if self.descriptor_version != EFI_MEMORY_DESCRIPTOR_VERSION {
+            return Err(Error::Unsupported);
+        }
+        if self.descriptor_size < size_of::<MemoryDescriptor>() {
+            return Err(Error::InvalidMemoryMap);
+        }
+        if self.size % self.descriptor_size != 0 {
+            return Err(Error::InvalidMemoryMap);
+        }
+        Ok(())
+    }
+}
+
+// 記述子ごとの一覧と、種類ごとの合計を表形式で出力する
+pub fn write_memory_map<W: fmt::Write>(w: &mut W, map: &MemoryMapHolder) -> fmt::Result {
+    writeln!(
+        w,
+        "{:<27} {:<37} {:>8} Attribute",
+        "Type", "Physical range", "Pages"
+    )?;
+    for e in map.iter() {
+        writeln!(w, "{e}")?;
+    }
+    write_memory_map_summary(w, map)
+}
+
+pub fn write_memory_map_summary<W: fmt::Write>(w: &mut W, map: &MemoryMapHolder) -> fmt::Result {
+    for memory_type in EfiMemoryType::ALL.iter() {
+        let pages = map.total_pages(*memory_type);
+        if pages == 0 {
+            continue;
+        }
+        writeln!(
+            w,
+            "{:<27} {:>8} pages {:>6} MiB",
+            memory_type,
+            pages,
+            pages * EFI_PAGE_SIZE / 1024 / 1024
+        )?;
+    }
+    Ok(())
 }
 
 impl Default for MemoryMapHolder {
@@ -120,11 +530,11 @@ impl<'a> Iterator for MemoryMapIterator<'a>{
     type Item = &'a MemoryDescriptor;
     fn next(&mut self) -> Option<&'a MemoryDescriptor> {
         if self.ofs >= self.map.size {
-            return None;
+            None
         }
         else {
             let e: &MemoryDescriptor = unsafe {
-                &*(self.map.buffer.as_ptr().add(self.ofs) as *const MemoryDescriptor)
+                &*(self.map.buffer.add(self.ofs) as *const MemoryDescriptor)
             };
             self.ofs += self.map.descriptor_size;
             Some(e)
@@ -142,7 +552,13 @@ pub struct EfiBootServicesTable {
         descripter_size: *mut usize,
         descriptor_version: *mut u32,
     ) -> EfiStatus,
-    _reserved1: [u64; 21],
+    allocate_pool: extern "win64" fn(
+        pool_type: EfiMemoryType,
+        size: usize,
+        buffer: *mut *mut EfiVoid,
+    ) -> EfiStatus,
+    free_pool: extern "win64" fn(buffer: *mut EfiVoid) -> EfiStatus,
+    _reserved1: [u64; 19],
     exit_boot_services: extern "win64" fn(
         image_handle: EfiHandle,
         map_key: usize,
@@ -156,30 +572,170 @@ pub struct EfiBootServicesTable {
 }
 
 impl EfiBootServicesTable{
-   pub fn get_memory_map(
+    pub fn allocate_pool(
+        &self,
+        pool_type: EfiMemoryType,
+        size: usize,
+    ) -> Result<*mut u8> {
+        let mut buffer = null_mut::<EfiVoid>();
+        let status = (self.allocate_pool)(pool_type, size, &mut buffer);
+        if status == EfiStatus::OUT_OF_RESOURCES {
+            return Err(Error::OutOfMemory);
+        }
+        status.into_result()?;
+        Ok(buffer)
+    }
+
+    /// # Safety
+    /// `buffer` must have been returned by `allocate_pool` and must not be used afterwards.
+    pub unsafe fn free_pool(&self, buffer: *mut u8) -> Result<()> {
+        (self.free_pool)(buffer).into_result()
+    }
+
+    // 足りなければバッファを確保し直して、メモリマップを取得する
+    pub fn get_memory_map(
         &self,
         map: &mut MemoryMapHolder,
-    ) -> EfiStatus {
+    ) -> Result<()> {
+        loop {
+            let mut size = map.buffer_size;
+            let status = (self.get_memory_map)(
+                &mut size,
+                map.buffer,
+                &mut map.map_key,
+                &mut map.descriptor_size,
+                &mut map.descriptor_version,
+            );
+            let descriptor_size = max(map.descriptor_size, size_of::<MemoryDescriptor>());
+            let has_slack = map.buffer_size.saturating_sub(size)
+                >= descriptor_size * MEMORY_MAP_MIN_SLACK_DESCRIPTORS;
+            if status == EfiStatus::BUFFER_TOO_SMALL || (status == EfiStatus::SUCCESS && !has_slack)
+            {
+                // sizeには必要なサイズが返ってくるので、余裕を持たせて確保し直す
+                let new_size = size + descriptor_size * MEMORY_MAP_SLACK_DESCRIPTORS;
+                if !map.buffer.is_null() {
+                    unsafe { self.free_pool(map.buffer)? };
+                    map.buffer = null_mut();
+                    map.buffer_size = 0;
+                }
+                map.buffer = self.allocate_pool(EfiMemoryType::LOADER_DATA, new_size)?;
+                map.buffer_size = new_size;
+                continue;
+            }
+            status.into_result()?;
+            map.size = size;
+            return map.validate();
+        }
+    }
+
+    // 確保済みのバッファにメモリマップを取り直す。ExitBootServicesに失敗した後は、
+    // GetMemoryMapしか呼べないので、AllocatePoolやFreePoolは使わない
+    fn refresh_memory_map(&self, map: &mut MemoryMapHolder) -> Result<()> {
+        let mut size = map.buffer_size;
         (self.get_memory_map)(
-            &mut map.size,
-            map.buffer.as_mut_ptr(),
+            &mut size,
+            map.buffer,
             &mut map.map_key,
             &mut map.descriptor_size,
             &mut map.descriptor_version,
         )
+        .into_result()?;
+        map.size = size;
+        map.validate()
+    }
+}
+
+#[repr(C)]
+struct EfiConfigurationTable {
+    vendor_guid: EfiGuid,
+    vendor_table: *const EfiVoid,
+}
+
+// タイムゾーンが不明なときの値。このときはUTCとみなす
+const EFI_UNSPECIFIED_TIMEZONE: i16 = 0x07ff;
+
+#[repr(C)]
+#[derive(Debug, Default)]
+struct EfiTime {
+    year: u16,
+    month: u8,
+    day: u8,
+    hour: u8,
+    minute: u8,
+    second: u8,
+    _pad1: u8,
+    nanosecond: u32,
+    time_zone: i16,
+    daylight: u8,
+    _pad2: u8,
+}
+const _: () = assert!(size_of::<EfiTime>() == 16);
+
+impl EfiTime {
+    // time_zoneはUTCからのずれ (分) で、UTC = ローカル時刻 - time_zone
+    fn to_utc(&self) -> Result<DateTime> {
+        let local = DateTime::new(
+            self.year,
+            self.month,
+            self.day,
+            self.hour,
+            self.minute,
+            self.second,
+        )?;
+        if self.time_zone == EFI_UNSPECIFIED_TIMEZONE {
+            return Ok(local);
+        }
+        let offset = self.time_zone as i64 * 60;
+        let timestamp = local.unix_timestamp() as i64 - offset;
+        Ok(DateTime::from_unix_timestamp(max(timestamp, 0) as u64))
+    }
+}
+
+#[repr(C)]
+pub struct EfiRuntimeServicesTable {
+    _reserved0: [u64; 3],
+    get_time: extern "win64" fn(time: *mut EfiTime, capabilities: *mut EfiVoid) -> EfiStatus,
+}
+
+impl EfiRuntimeServicesTable {
+    pub fn get_time(&self) -> Result<DateTime> {
+        let mut time = EfiTime::default();
+        (self.get_time)(&mut time, null_mut()).into_result()?;
+        time.to_utc()
     }
 }
 
 #[repr(C)]
 pub struct EfiSystemTable {
-    _reserved0: [u64; 12],
+    _reserved0: [u64; 11],
+    runtime_services: &'static EfiRuntimeServicesTable,
     boot_services: &'static EfiBootServicesTable,
+    number_of_table_entries: usize,
+    configuration_table: *const EfiConfigurationTable,
 }
 
 impl EfiSystemTable {
     pub fn boot_services(&self) -> &EfiBootServicesTable {
         self.boot_services
     }
+
+    pub fn runtime_services(&self) -> &EfiRuntimeServicesTable {
+        self.runtime_services
+    }
+
+    fn configuration_tables(&self) -> &[EfiConfigurationTable] {
+        unsafe {
+            core::slice::from_raw_parts(self.configuration_table, self.number_of_table_entries)
+        }
+    }
+
+    // ACPI 2.0以降のRSDPの物理アドレス。ACPIの表はブートサービスを抜けた後も残る
+    pub fn acpi_rsdp_addr(&self) -> Option<usize> {
+        self.configuration_tables()
+            .iter()
+            .find(|e| e.vendor_guid == EFI_ACPI_20_TABLE_GUID)
+            .map(|e| e.vendor_table as usize)
+    }
 }
 
 #[repr(C)]
@@ -223,9 +779,10 @@ fn locate_graphics_protocol<'a>(
             null_mut::<EfiVoid>(),
             &mut graphic_output_protocol as *mut *mut EfiGraphicsOutputProtocol as *mut *mut EfiVoid,
         );
-        if status != EfiStatus::Success {
-            return Err("Failed to locate graphics output protocol");
+        if status == EfiStatus::NOT_FOUND {
+            return Err(Error::ProtocolNotFound);
         }
+        status.into_result()?;
         Ok(&*graphic_output_protocol)
     }
 }
@@ -238,6 +795,14 @@ pub struct VramBufferInfo {
     pixels_per_line: i64,
 }
 
+impl VramBufferInfo {
+    // フレームバッファの物理アドレスと大きさ
+    pub fn frame_buffer_range(&self) -> (usize, usize) {
+        let size = self.pixels_per_line * self.height * self.bytes_per_pixel();
+        (self.buf as usize, size as usize)
+    }
+}
+
 impl Bitmap for VramBufferInfo {
     fn bytes_per_pixel(&self) -> i64 {
         4
@@ -306,15 +871,16 @@ pub fn exit_from_efi_boot_services(
     efi_system_table: &EfiSystemTable,
     memory_map: &mut MemoryMapHolder,
 ) {
-    loop {
-        let status = efi_system_table.boot_services.get_memory_map(memory_map);
-        assert_eq!(status, EfiStatus::Success);
-        let status = (efi_system_table.boot_services.exit_boot_services)(
-            image_handle,
-            memory_map.map_key,
-        );
-        if status == EfiStatus::Success {
-            break;
-        }
+    let boot_services = efi_system_table.boot_services;
+    boot_services
+        .get_memory_map(memory_map)
+        .expect("Failed to get memory map");
+    while (boot_services.exit_boot_services)(image_handle, memory_map.map_key)
+        != EfiStatus::SUCCESS
+    {
+        // マップが変わっていたので取り直す。バッファに収まらなければ続けられない
+        boot_services
+            .refresh_memory_map(memory_map)
+            .expect("Memory map outgrew its buffer after ExitBootServices failed");
     }
 }
\ No newline at end of file
@@ -1,4 +1,17 @@
+pub mod apic;
+pub mod gdt;
+pub mod i8042;
+pub mod idt;
+pub mod keyboard;
+pub mod mouse;
+pub mod paging;
+pub mod port;
+pub mod rtc;
+pub mod serial;
+pub mod timer;
+
 use core::arch::asm;
+use port::Port;
 
 
 pub fn hlt() {
@@ -7,12 +20,161 @@ pub fn hlt() {
     }
 }
 
+// 1バイトのポートを一度だけ読み書きするときの簡易版
 pub fn write_io_port_u8(port: u16, value: u8) {
+    Port::<u8>::new(port).write(value)
+}
+
+pub fn read_io_port_u8(port: u16) -> u8 {
+    Port::<u8>::new(port).read()
+}
+
+pub const RFLAGS_IF: u64 = 1 << 9;
+
+pub fn read_rflags() -> u64 {
+    let rflags: u64;
+    unsafe {
+        asm!(
+            "pushfq",
+            "pop {}",
+            out(reg) rflags,
+        );
+    }
+    rflags
+}
+
+pub fn are_interrupts_enabled() -> bool {
+    read_rflags() & RFLAGS_IF != 0
+}
+
+pub fn disable_interrupts() {
+    unsafe {
+        asm!("cli");
+    }
+}
+
+pub fn enable_interrupts() {
+    unsafe {
+        asm!("sti");
+    }
+}
+
+pub fn read_rsp() -> u64 {
+    let rsp: u64;
+    unsafe {
+        asm!(
+            "mov {}, rsp",
+            out(reg) rsp,
+        );
+    }
+    rsp
+}
+
+// 直前のページフォルトを起こした線形アドレス
+pub fn read_cr2() -> u64 {
+    let cr2: u64;
     unsafe {
         asm!(
-            "out dx, al",
-            in("dx") port,
-            in("al") value,
+            "mov {}, cr2",
+            out(reg) cr2,
         );
     }
-}
\ No newline at end of file
+    cr2
+}
+
+pub const CR0_WP: u64 = 1 << 16;
+
+pub fn read_cr0() -> u64 {
+    let cr0: u64;
+    unsafe {
+        asm!(
+            "mov {}, cr0",
+            out(reg) cr0,
+        );
+    }
+    cr0
+}
+
+/// # Safety
+///
+/// Changing control bits such as paging or protection enable can break the running kernel.
+pub unsafe fn write_cr0(cr0: u64) {
+    asm!(
+        "mov cr0, {}",
+        in(reg) cr0,
+    );
+}
+
+pub fn read_cr3() -> u64 {
+    let cr3: u64;
+    unsafe {
+        asm!(
+            "mov {}, cr3",
+            out(reg) cr3,
+        );
+    }
+    cr3
+}
+
+/// # Safety
+///
+/// `cr3` must point to a valid PML4 that maps the running code, stack and data.
+pub unsafe fn write_cr3(cr3: u64) {
+    asm!(
+        "mov cr3, {}",
+        in(reg) cr3,
+    );
+}
+
+// 1ページ分のTLBエントリを無効化する
+pub fn invlpg(addr: usize) {
+    unsafe {
+        asm!(
+            "invlpg [{}]",
+            in(reg) addr,
+        );
+    }
+}
+
+pub const MSR_EFER: u32 = 0xc000_0080;
+pub const EFER_NXE: u64 = 1 << 11;
+
+/// # Safety
+///
+/// `msr` must be a model-specific register implemented by the CPU.
+pub unsafe fn read_msr(msr: u32) -> u64 {
+    let lo: u32;
+    let hi: u32;
+    asm!(
+        "rdmsr",
+        in("ecx") msr,
+        out("eax") lo,
+        out("edx") hi,
+    );
+    ((hi as u64) << 32) | lo as u64
+}
+
+/// # Safety
+///
+/// `msr` must be implemented by the CPU, and `value` must be valid for it.
+pub unsafe fn write_msr(msr: u32, value: u64) {
+    asm!(
+        "wrmsr",
+        in("ecx") msr,
+        in("eax") value as u32,
+        in("edx") (value >> 32) as u32,
+    );
+}
+
+pub fn rdtsc() -> u64 {
+    let lo: u32;
+    let hi: u32;
+    unsafe {
+        asm!(
+            "rdtsc",
+            out("eax") lo,
+            out("edx") hi,
+        );
+    }
+    ((hi as u64) << 32) | lo as u64
+}
@@ -0,0 +1,313 @@
+use crate::acpi;
+use crate::acpi::InterruptOverride;
+use crate::mutex::Mutex;
+use crate::result::Error;
+use crate::result::Result;
+use crate::x86::idt;
+use crate::x86::idt::InterruptFrame;
+use crate::x86::idt::NUM_EXCEPTIONS;
+use crate::x86::idt::NUM_VECTORS;
+use crate::x86::paging;
+use crate::x86::paging::CacheMode;
+use crate::x86::read_msr;
+use crate::x86::serial::SerialPort;
+use crate::x86::serial::COM1;
+use crate::x86::write_io_port_u8;
+use crate::x86::write_msr;
+use alloc::vec::Vec;
+use core::fmt::Write;
+use core::ptr::read_volatile;
+use core::ptr::write_volatile;
+use core::sync::atomic::AtomicUsize;
+use core::sync::atomic::Ordering;
+
+// ISAのIRQ nは、ベクタIRQ_VECTOR_BASE + nに届ける
+pub const IRQ_VECTOR_BASE: u8 = 0x20;
+pub const NUM_ISA_IRQS: usize = 16;
+pub const SPURIOUS_VECTOR: u8 = 0xff;
+// 8259が万一割り込みを出しても、IRQのベクタと区別できる位置に逃がしておく
+const PIC_VECTOR_BASE: u8 = 0xe0;
+
+const PIC1_COMMAND: u16 = 0x20;
+const PIC1_DATA: u16 = 0x21;
+const PIC2_COMMAND: u16 = 0xa0;
+const PIC2_DATA: u16 = 0xa1;
+const PIC_ICW1_INIT_WITH_ICW4: u8 = 0x11;
+const PIC_ICW4_8086: u8 = 0x01;
+
+const MSR_IA32_APIC_BASE: u32 = 0x1b;
+const APIC_BASE_ENABLE: u64 = 1 << 11;
+
+const LAPIC_MMIO_SIZE: usize = 0x400;
+pub(crate) const LAPIC_ID: usize = 0x20;
+pub(crate) const LAPIC_TPR: usize = 0x80;
+pub(crate) const LAPIC_EOI: usize = 0xb0;
+pub(crate) const LAPIC_SVR: usize = 0xf0;
+pub(crate) const LAPIC_ICR_LOW: usize = 0x300;
+pub(crate) const LAPIC_ICR_HIGH: usize = 0x310;
+pub(crate) const LAPIC_LVT_TIMER: usize = 0x320;
+pub(crate) const LAPIC_LVT_LINT0: usize = 0x350;
+pub(crate) const LAPIC_LVT_ERROR: usize = 0x370;
+const SVR_APIC_ENABLE: u32 = 1 << 8;
+pub(crate) const LVT_MASKED: u32 = 1 << 16;
+const ICR_DELIVERY_PENDING: u32 = 1 << 12;
+const ICR_DEST_SELF: u32 = 1 << 18;
+
+const IOAPIC_MMIO_SIZE: usize = 0x20;
+const IOAPIC_REGSEL: usize = 0x00;
+const IOAPIC_WINDOW: usize = 0x10;
+const IOAPIC_VERSION: u32 = 0x01;
+const IOAPIC_REDIRECTION_TABLE: u32 = 0x10;
+const REDIRECTION_ACTIVE_LOW: u32 = 1 << 13;
+const REDIRECTION_LEVEL_TRIGGERED: u32 = 1 << 15;
+const REDIRECTION_MASKED: u32 = 1 << 16;
+
+// APICのレジスタは恒等マップ上でアクセスする。
+// MTRRの設定に頼らないよう、initでキャッシュ不可のページに対応付け直す
+static LOCAL_APIC_BASE: AtomicUsize = AtomicUsize::new(0);
+
+pub(crate) fn local_apic_read(reg: usize) -> u32 {
+    let base = LOCAL_APIC_BASE.load(Ordering::Relaxed);
+    unsafe { read_volatile((base + reg) as *const u32) }
+}
+
+pub(crate) fn local_apic_write(reg: usize, value: u32) {
+    let base = LOCAL_APIC_BASE.load(Ordering::Relaxed);
+    unsafe { write_volatile((base + reg) as *mut u32, value) }
+}
+
+pub fn local_apic_id() -> u8 {
+    (local_apic_read(LAPIC_ID) >> 24) as u8
+}
+
+// 割り込みの処理が終わったことをローカルAPICに伝える
+pub fn end_of_interrupt() {
+    local_apic_write(LAPIC_EOI, 0);
+}
+
+// 自分自身にプロセッサ間割り込みを送る
+pub fn send_self_ipi(vector: u8) {
+    local_apic_write(LAPIC_ICR_HIGH, 0);
+    local_apic_write(LAPIC_ICR_LOW, ICR_DEST_SELF | vector as u32);
+    while local_apic_read(LAPIC_ICR_LOW) & ICR_DELIVERY_PENDING != 0 {}
+}
+
+struct IoApic {
+    base: usize,
+    gsi_base: u32,
+    num_entries: u32,
+}
+
+impl IoApic {
+    fn read(&self, reg: u32) -> u32 {
+        unsafe {
+            write_volatile((self.base + IOAPIC_REGSEL) as *mut u32, reg);
+            read_volatile((self.base + IOAPIC_WINDOW) as *const u32)
+        }
+    }
+    fn write(&self, reg: u32, value: u32) {
+        unsafe {
+            write_volatile((self.base + IOAPIC_REGSEL) as *mut u32, reg);
+            write_volatile((self.base + IOAPIC_WINDOW) as *mut u32, value);
+        }
+    }
+    fn contains(&self, gsi: u32) -> bool {
+        (self.gsi_base..self.gsi_base + self.num_entries).contains(&gsi)
+    }
+    // 上位32ビットを先に書き、設定し終えてからマスクを外す
+    fn set_redirection(&self, gsi: u32, low: u32, high: u32) {
+        let reg = IOAPIC_REDIRECTION_TABLE + (gsi - self.gsi_base) * 2;
+        self.write(reg, REDIRECTION_MASKED);
+        self.write(reg + 1, high);
+        self.write(reg, low);
+    }
+}
+
+struct InterruptRouting {
+    io_apics: Vec<IoApic>,
+    overrides: Vec<InterruptOverride>,
+}
+
+static ROUTING: Mutex<Option<InterruptRouting>> = Mutex::new(None);
+
+pub type IrqHandler = fn();
+
+static IRQ_HANDLERS: Mutex<[Option<IrqHandler>; NUM_ISA_IRQS]> = Mutex::new([None; NUM_ISA_IRQS]);
+
+// 8259を使わないよう、ベクタを移してからすべてのIRQをマスクする
+fn disable_pic() {
+    write_io_port_u8(PIC1_COMMAND, PIC_ICW1_INIT_WITH_ICW4);
+    write_io_port_u8(PIC2_COMMAND, PIC_ICW1_INIT_WITH_ICW4);
+    write_io_port_u8(PIC1_DATA, PIC_VECTOR_BASE);
+    write_io_port_u8(PIC2_DATA, PIC_VECTOR_BASE + 8);
+    // マスタのIRQ2にスレーブがつながっている
+    write_io_port_u8(PIC1_DATA, 1 << 2);
+    write_io_port_u8(PIC2_DATA, 2);
+    write_io_port_u8(PIC1_DATA, PIC_ICW4_8086);
+    write_io_port_u8(PIC2_DATA, PIC_ICW4_8086);
+    write_io_port_u8(PIC1_DATA, 0xff);
+    write_io_port_u8(PIC2_DATA, 0xff);
+}
+
+// 8259を止め、ローカルAPICを有効にして、MADTにあるI/O APICの入力をすべてマスクする。
+// IRQはset_irq_handlerで登録したときに、はじめてI/O APICからこのCPUに届くようになる
+pub fn init() -> Result<()> {
+    let madt = acpi::madt()?;
+    if madt.has_8259 {
+        disable_pic();
+    }
+    for vector in NUM_EXCEPTIONS..NUM_VECTORS {
+        idt::set_handler(vector as u8, handle_unexpected_interrupt)?;
+    }
+    idt::set_handler(SPURIOUS_VECTOR, |_| {})?;
+
+    unsafe {
+        let apic_base = read_msr(MSR_IA32_APIC_BASE);
+        write_msr(MSR_IA32_APIC_BASE, apic_base | APIC_BASE_ENABLE);
+    }
+    paging::map_mmio(madt.local_apic_addr, LAPIC_MMIO_SIZE, CacheMode::Uncached)?;
+    LOCAL_APIC_BASE.store(madt.local_apic_addr, Ordering::Relaxed);
+    local_apic_write(LAPIC_TPR, 0);
+    local_apic_write(LAPIC_LVT_TIMER, LVT_MASKED);
+    local_apic_write(LAPIC_LVT_LINT0, LVT_MASKED);
+    local_apic_write(LAPIC_LVT_ERROR, LVT_MASKED);
+    local_apic_write(LAPIC_SVR, SVR_APIC_ENABLE | SPURIOUS_VECTOR as u32);
+
+    let mut io_apics = Vec::new();
+    for info in madt.io_apics.iter() {
+        paging::map_mmio(info.addr, IOAPIC_MMIO_SIZE, CacheMode::Uncached)?;
+        let mut io_apic = IoApic {
+            base: info.addr,
+            gsi_base: info.gsi_base,
+            num_entries: 0,
+        };
+        io_apic.num_entries = (io_apic.read(IOAPIC_VERSION) >> 16 & 0xff) + 1;
+        for gsi in io_apic.gsi_base..io_apic.gsi_base + io_apic.num_entries {
+            io_apic.set_redirection(gsi, REDIRECTION_MASKED, 0);
+        }
+        io_apics.push(io_apic);
+    }
+    *ROUTING.lock() = Some(InterruptRouting {
+        io_apics,
+        overrides: madt.overrides,
+    });
+    Ok(())
+}
+
+// ISAのIRQにハンドラを登録し、I/O APICからこのCPUのIRQ_VECTOR_BASE + irqに届くようにする
+pub fn set_irq_handler(irq: u8, handler: IrqHandler) -> Result<()> {
+    if irq as usize >= NUM_ISA_IRQS {
+        return Err(Error::InvalidParameter);
+    }
+    IRQ_HANDLERS.lock()[irq as usize] = Some(handler);
+    let vector = IRQ_VECTOR_BASE + irq;
+    idt::set_handler(vector, handle_irq)?;
+    route_irq(irq, vector)
+}
+
+// ISAのIRQは、MADTの上書き指定がなければ同じ番号のGSIにアクティブハイ、エッジトリガでつながる
+fn route_irq(irq: u8, vector: u8) -> Result<()> {
+    let routing = ROUTING.lock();
+    let routing = routing.as_ref().ok_or(Error::NotInitialized)?;
+    let (gsi, active_low, level_triggered) = match routing.overrides.iter().find(|e| e.irq == irq) {
+        Some(e) => (e.gsi, e.active_low, e.level_triggered),
+        None => (irq as u32, false, false),
+    };
+    let io_apic = routing
+        .io_apics
+        .iter()
+        .find(|e| e.contains(gsi))
+        .ok_or(Error::DeviceNotFound)?;
+    let mut low = vector as u32;
+    if active_low {
+        low |= REDIRECTION_ACTIVE_LOW;
+    }
+    if level_triggered {
+        low |= REDIRECTION_LEVEL_TRIGGERED;
+    }
+    io_apic.set_redirection(gsi, low, (local_apic_id() as u32) << 24);
+    Ok(())
+}
+
+fn handle_irq(frame: &mut InterruptFrame) {
+    let irq = frame.vector as usize - IRQ_VECTOR_BASE as usize;
+    let handler = IRQ_HANDLERS.lock()[irq];
+    if let Some(handler) = handler {
+        handler();
+    }
+    end_of_interrupt();
+}
+
+fn handle_unexpected_interrupt(frame: &mut InterruptFrame) {
+    let mut serial = SerialPort::new(COM1);
+    let _ = writeln!(serial, "Unexpected interrupt (vector {:#x})", frame.vector);
+    end_of_interrupt();
+}
+
+#[cfg(test)]
+mod test {
+    use super::local_apic_id;
+    use super::send_self_ipi;
+    use super::set_irq_handler;
+    use super::end_of_interrupt;
+    use crate::acpi;
+    use crate::result::Error;
+    use crate::x86::are_interrupts_enabled;
+    use crate::x86::idt;
+    use crate::x86::idt::InterruptFrame;
+    use crate::x86::paging::CacheMode;
+    use crate::x86::paging::KERNEL_ADDRESS_SPACE;
+    use core::sync::atomic::AtomicUsize;
+    use core::sync::atomic::Ordering;
+
+    const TEST_VECTOR: u8 = 0x40;
+    static TEST_INTERRUPT_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+    fn handle_test_interrupt(_frame: &mut InterruptFrame) {
+        TEST_INTERRUPT_COUNT.fetch_add(1, Ordering::SeqCst);
+        end_of_interrupt();
+    }
+
+    #[test_case]
+    fn self_ipi_reaches_handler() {
+        assert!(are_interrupts_enabled());
+        idt::set_handler(TEST_VECTOR, handle_test_interrupt).unwrap();
+        let count = TEST_INTERRUPT_COUNT.load(Ordering::SeqCst);
+        send_self_ipi(TEST_VECTOR);
+        for _ in 0..1_000_000 {
+            if TEST_INTERRUPT_COUNT.load(Ordering::SeqCst) != count {
+                break;
+            }
+            core::hint::spin_loop();
+        }
+        assert_eq!(TEST_INTERRUPT_COUNT.load(Ordering::SeqCst), count + 1);
+    }
+
+    #[test_case]
+    fn local_apic_is_listed_in_madt() {
+        let madt = acpi::madt().unwrap();
+        assert!(madt.local_apic_ids.contains(&local_apic_id()));
+    }
+
+    #[test_case]
+    fn apic_registers_are_mapped_uncached() {
+        let madt = acpi::madt().unwrap();
+        let space = KERNEL_ADDRESS_SPACE.lock();
+        let space = space.as_ref().unwrap();
+        let addrs = madt.io_apics.iter().map(|e| e.addr);
+        for addr in core::iter::once(madt.local_apic_addr).chain(addrs) {
+            let (phys, flags) = space.translate_with_flags(addr).unwrap();
+            assert_eq!(phys, addr);
+            assert!(flags.contains(CacheMode::Uncached.flags()));
+        }
+    }
+
+    #[test_case]
+    fn exceptions_and_non_isa_irqs_are_rejected() {
+        assert_eq!(
+            idt::set_handler(14, handle_test_interrupt),
+            Err(Error::InvalidParameter)
+        );
+        assert_eq!(set_irq_handler(16, || {}), Err(Error::InvalidParameter));
+    }
+}
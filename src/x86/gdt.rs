@@ -0,0 +1,213 @@
+use crate::x86::disable_interrupts;
+use core::arch::asm;
+use core::mem::size_of;
+use core::ptr::addr_of;
+use core::ptr::addr_of_mut;
+
+pub const KERNEL_CS: u16 = 1 << 3;
+pub const KERNEL_DS: u16 = 2 << 3;
+// sysretの都合で、ユーザーのデータセグメントをコードセグメントの前に置く
+pub const USER_DS: u16 = 3 << 3 | 3;
+pub const USER_CS: u16 = 4 << 3 | 3;
+// TSSの記述子は16バイトなので、GDTのエントリを2つ使う
+pub const TSS_SELECTOR: u16 = 5 << 3;
+const NUM_GDT_ENTRIES: usize = 7;
+
+// IDTのゲートから指定するISTの番号 (0はISTを使わないことを表すので1始まり)
+pub const IST_DOUBLE_FAULT: u8 = 1;
+pub const IST_NMI: u8 = 2;
+pub const IST_MACHINE_CHECK: u8 = 3;
+const NUM_IST_STACKS: usize = 3;
+const STACK_SIZE: usize = 16 * 1024;
+
+const DESC_LIMIT_MAX: u64 = 0xffff | 0xf << 48;
+const DESC_ACCESSED: u64 = 1 << 40;
+const DESC_WRITABLE: u64 = 1 << 41; // コードセグメントでは読み出し可
+const DESC_EXECUTABLE: u64 = 1 << 43;
+const DESC_CODE_OR_DATA: u64 = 1 << 44;
+const DESC_DPL_USER: u64 = 3 << 45;
+const DESC_PRESENT: u64 = 1 << 47;
+const DESC_LONG_MODE: u64 = 1 << 53;
+const DESC_DEFAULT_SIZE_32: u64 = 1 << 54;
+const DESC_GRANULARITY_4K: u64 = 1 << 55;
+const DESC_TYPE_TSS_AVAILABLE: u64 = 0x9 << 40;
+
+const KERNEL_CODE: u64 = DESC_LIMIT_MAX
+    | DESC_ACCESSED
+    | DESC_WRITABLE
+    | DESC_EXECUTABLE
+    | DESC_CODE_OR_DATA
+    | DESC_PRESENT
+    | DESC_LONG_MODE
+    | DESC_GRANULARITY_4K;
+const KERNEL_DATA: u64 = DESC_LIMIT_MAX
+    | DESC_ACCESSED
+    | DESC_WRITABLE
+    | DESC_CODE_OR_DATA
+    | DESC_PRESENT
+    | DESC_DEFAULT_SIZE_32
+    | DESC_GRANULARITY_4K;
+const USER_CODE: u64 = KERNEL_CODE | DESC_DPL_USER;
+const USER_DATA: u64 = KERNEL_DATA | DESC_DPL_USER;
+
+// 64ビットモードのTSS。割り込みで特権レベルが変わるときのスタック (rsp) と、
+// IDTのゲートで指定された場合に切り替えるスタック (ist) を持つ
+#[repr(C, packed(4))]
+pub struct TaskStateSegment {
+    _reserved0: u32,
+    rsp: [u64; 3],
+    _reserved1: u64,
+    ist: [u64; 7],
+    _reserved2: u64,
+    _reserved3: u16,
+    iomap_base: u16,
+}
+const _: () = assert!(size_of::<TaskStateSegment>() == 104);
+
+impl TaskStateSegment {
+    const fn new() -> Self {
+        Self {
+            _reserved0: 0,
+            rsp: [0; 3],
+            _reserved1: 0,
+            ist: [0; 7],
+            _reserved2: 0,
+            _reserved3: 0,
+            // I/Oパーミッションビットマップは置かない
+            iomap_base: size_of::<Self>() as u16,
+        }
+    }
+    fn descriptor(&self) -> [u64; 2] {
+        let base = self as *const Self as u64;
+        let limit = (size_of::<Self>() - 1) as u64;
+        let low = (limit & 0xffff)
+            | (base & 0xff_ffff) << 16
+            | DESC_TYPE_TSS_AVAILABLE
+            | DESC_PRESENT
+            | (limit >> 16 & 0xf) << 48
+            | (base >> 24 & 0xff) << 56;
+        [low, base >> 32]
+    }
+}
+
+#[repr(C, align(16))]
+struct Stack([u8; STACK_SIZE]);
+const EMPTY_STACK: Stack = Stack([0; STACK_SIZE]);
+
+impl Stack {
+    fn top(&self) -> u64 {
+        self.0.as_ptr_range().end as u64
+    }
+}
+
+#[repr(C, align(16))]
+struct GlobalDescriptorTable {
+    entries: [u64; NUM_GDT_ENTRIES],
+}
+
+#[repr(C, packed)]
+struct DescriptorTablePointer {
+    limit: u16,
+    base: u64,
+}
+
+static mut GDT: GlobalDescriptorTable = GlobalDescriptorTable {
+    entries: [0; NUM_GDT_ENTRIES],
+};
+static mut TSS: TaskStateSegment = TaskStateSegment::new();
+static mut PRIVILEGE_STACK: Stack = EMPTY_STACK;
+static mut IST_STACKS: [Stack; NUM_IST_STACKS] = [EMPTY_STACK; NUM_IST_STACKS];
+
+// ファームウェアが残したGDTを、カーネル自身のGDTとTSSで置き換える。
+// ファームウェアのIDTは古いセレクタを指したままなので、割り込みは禁止しておく
+pub fn init() {
+    disable_interrupts();
+    unsafe {
+        let tss = &mut *addr_of_mut!(TSS);
+        tss.rsp[0] = (*addr_of!(PRIVILEGE_STACK)).top();
+        for (i, stack) in (*addr_of!(IST_STACKS)).iter().enumerate() {
+            tss.ist[i] = stack.top();
+        }
+        let [tss_low, tss_high] = tss.descriptor();
+        let gdt = &mut *addr_of_mut!(GDT);
+        gdt.entries = [0, KERNEL_CODE, KERNEL_DATA, USER_DATA, USER_CODE, tss_low, tss_high];
+        load_gdt(gdt);
+        reload_segment_registers();
+        load_task_register(TSS_SELECTOR);
+    }
+}
+
+unsafe fn load_gdt(gdt: &GlobalDescriptorTable) {
+    let pointer = DescriptorTablePointer {
+        limit: (size_of::<GlobalDescriptorTable>() - 1) as u16,
+        base: gdt as *const GlobalDescriptorTable as u64,
+    };
+    asm!("lgdt [{}]", in(reg) &pointer, options(readonly, nostack, preserves_flags));
+}
+
+// CSはmovで書き換えられないので、far returnで読み込み直す
+unsafe fn reload_segment_registers() {
+    asm!(
+        "push {cs}",
+        "lea {tmp}, [rip + 2f]",
+        "push {tmp}",
+        "retfq",
+        "2:",
+        "mov ds, {ds:x}",
+        "mov es, {ds:x}",
+        "mov fs, {ds:x}",
+        "mov gs, {ds:x}",
+        "mov ss, {ds:x}",
+        cs = in(reg) KERNEL_CS as u64,
+        ds = in(reg) KERNEL_DS as u64,
+        tmp = out(reg) _,
+        options(preserves_flags),
+    );
+}
+
+unsafe fn load_task_register(selector: u16) {
+    asm!("ltr {:x}", in(reg) selector, options(nostack, preserves_flags));
+}
+
+#[cfg(test)]
+mod test {
+    use super::DescriptorTablePointer;
+    use super::GDT;
+    use super::KERNEL_CS;
+    use super::KERNEL_DS;
+    use super::TSS;
+    use super::TSS_SELECTOR;
+    use core::arch::asm;
+    use core::ptr::addr_of;
+
+    #[test_case]
+    fn segment_registers_use_kernel_gdt() {
+        let cs: u16;
+        let ss: u16;
+        let tr: u16;
+        let mut gdtr = DescriptorTablePointer { limit: 0, base: 0 };
+        unsafe {
+            asm!("mov {:x}, cs", out(reg) cs);
+            asm!("mov {:x}, ss", out(reg) ss);
+            asm!("str {:x}", out(reg) tr);
+            asm!("sgdt [{}]", in(reg) &mut gdtr);
+        }
+        assert_eq!(cs, KERNEL_CS);
+        assert_eq!(ss, KERNEL_DS);
+        assert_eq!(tr, TSS_SELECTOR);
+        let base = gdtr.base;
+        assert_eq!(base, unsafe { addr_of!(GDT) } as u64);
+    }
+
+    #[test_case]
+    fn tss_has_aligned_ist_stacks() {
+        let tss = unsafe { &*addr_of!(TSS) };
+        let ist = tss.ist;
+        for top in &ist[..super::NUM_IST_STACKS] {
+            assert_ne!(*top, 0);
+            assert_eq!(*top % 16, 0);
+        }
+        let rsp = tss.rsp;
+        assert_ne!(rsp[0], 0);
+    }
+}
@@ -0,0 +1,219 @@
+use crate::mutex::Mutex;
+use crate::result::Error;
+use crate::result::Result;
+use crate::x86::port::Port;
+use crate::x86::timer;
+use core::hint::spin_loop;
+use core::time::Duration;
+
+const PORT_DATA: Port<u8> = Port::new(0x60);
+const PORT_STATUS: Port<u8> = Port::new(0x64);
+const PORT_COMMAND: Port<u8> = Port::new(0x64);
+
+const STATUS_OUTPUT_FULL: u8 = 1 << 0;
+const STATUS_INPUT_FULL: u8 = 1 << 1;
+// 出力バッファのデータが、補助ポート (マウス) から来たことを表す
+const STATUS_AUX_DATA: u8 = 1 << 5;
+
+const CMD_READ_CONFIG: u8 = 0x20;
+const CMD_WRITE_CONFIG: u8 = 0x60;
+const CMD_DISABLE_AUX: u8 = 0xa7;
+const CMD_ENABLE_AUX: u8 = 0xa8;
+const CMD_TEST_AUX: u8 = 0xa9;
+const CMD_SELF_TEST: u8 = 0xaa;
+const CMD_TEST_KEYBOARD: u8 = 0xab;
+const CMD_DISABLE_KEYBOARD: u8 = 0xad;
+const CMD_ENABLE_KEYBOARD: u8 = 0xae;
+const CMD_WRITE_AUX: u8 = 0xd4;
+
+const CONFIG_KEYBOARD_IRQ: u8 = 1 << 0;
+const CONFIG_AUX_IRQ: u8 = 1 << 1;
+const CONFIG_KEYBOARD_CLOCK_DISABLED: u8 = 1 << 4;
+const CONFIG_AUX_CLOCK_DISABLED: u8 = 1 << 5;
+// コントローラがスキャンコードセット2をセット1に変換して渡す
+pub const CONFIG_TRANSLATION: u8 = 1 << 6;
+
+const SELF_TEST_PASSED: u8 = 0x55;
+const PORT_TEST_PASSED: u8 = 0x00;
+
+const DEVICE_RESET: u8 = 0xff;
+const DEVICE_ENABLE_SCANNING: u8 = 0xf4;
+const DEVICE_ACK: u8 = 0xfa;
+const DEVICE_RESEND: u8 = 0xfe;
+const DEVICE_SELF_TEST_PASSED: u8 = 0xaa;
+const MAX_RESENDS: usize = 3;
+
+const TIMEOUT: Duration = Duration::from_millis(100);
+// デバイスのリセットは、自己診断が終わるまで時間がかかる
+const RESET_TIMEOUT: Duration = Duration::from_millis(1000);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Ps2Port {
+    Keyboard,
+    Aux,
+}
+
+// コントローラの設定バイトを読み書きする間に、割り込みハンドラが割り込まないようにする
+static CONTROLLER: Mutex<()> = Mutex::new(());
+
+fn wait_until(timeout: Duration, condition: impl Fn() -> bool) -> Result<()> {
+    let deadline = timer::now() + timeout;
+    while !condition() {
+        if timer::now() > deadline {
+            return Err(Error::Timeout);
+        }
+        spin_loop();
+    }
+    Ok(())
+}
+
+fn read_status() -> u8 {
+    PORT_STATUS.read()
+}
+
+fn read_data() -> u8 {
+    PORT_DATA.read()
+}
+
+// 割り込みハンドラから、そのポートのデバイスが送ってきたデータを1バイト読む
+pub fn read_port_data(port: Ps2Port) -> Option<u8> {
+    let status = read_status();
+    let is_aux = status & STATUS_AUX_DATA != 0;
+    if status & STATUS_OUTPUT_FULL == 0 || is_aux != (port == Ps2Port::Aux) {
+        return None;
+    }
+    Some(read_data())
+}
+
+fn write_command(command: u8) -> Result<()> {
+    wait_until(TIMEOUT, || read_status() & STATUS_INPUT_FULL == 0)?;
+    PORT_COMMAND.write(command);
+    Ok(())
+}
+
+fn write_data(data: u8) -> Result<()> {
+    wait_until(TIMEOUT, || read_status() & STATUS_INPUT_FULL == 0)?;
+    PORT_DATA.write(data);
+    Ok(())
+}
+
+fn read_response(timeout: Duration) -> Result<u8> {
+    wait_until(timeout, || read_status() & STATUS_OUTPUT_FULL != 0)?;
+    Ok(read_data())
+}
+
+fn flush_output() {
+    while read_status() & STATUS_OUTPUT_FULL != 0 {
+        read_data();
+    }
+}
+
+fn read_config() -> Result<u8> {
+    write_command(CMD_READ_CONFIG)?;
+    read_response(TIMEOUT)
+}
+
+fn write_config(config: u8) -> Result<()> {
+    write_command(CMD_WRITE_CONFIG)?;
+    write_data(config)
+}
+
+// デバイスからの応答を1バイト読む。その間に届いた他のポートのデータは捨てる
+pub fn receive_from_device(port: Ps2Port) -> Result<u8> {
+    receive_with_timeout(port, TIMEOUT)
+}
+
+fn receive_with_timeout(port: Ps2Port, timeout: Duration) -> Result<u8> {
+    let deadline = timer::now() + timeout;
+    loop {
+        let remaining = deadline.saturating_sub(timer::now());
+        wait_until(remaining, || read_status() & STATUS_OUTPUT_FULL != 0)?;
+        if let Some(data) = read_port_data(port) {
+            return Ok(data);
+        }
+        read_data();
+    }
+}
+
+// デバイスにコマンドを送り、ACKを待つ。再送を求められたら送り直す
+pub fn send_to_device(port: Ps2Port, command: u8) -> Result<()> {
+    for _ in 0..MAX_RESENDS {
+        if port == Ps2Port::Aux {
+            write_command(CMD_WRITE_AUX)?;
+        }
+        write_data(command)?;
+        match receive_from_device(port)? {
+            DEVICE_ACK => return Ok(()),
+            DEVICE_RESEND => continue,
+            response => return Err(Error::UnexpectedResponse(response)),
+        }
+    }
+    Err(Error::UnexpectedResponse(DEVICE_RESEND))
+}
+
+// デバイスをリセットし、自己診断に通ったらスキャン (マウスではデータの送信) を始めさせる
+pub fn reset_device(port: Ps2Port) -> Result<()> {
+    send_to_device(port, DEVICE_RESET)?;
+    if receive_with_timeout(port, RESET_TIMEOUT)? != DEVICE_SELF_TEST_PASSED {
+        return Err(Error::DeviceNotFound);
+    }
+    // マウスはリセット後にデバイスIDも送ってくる
+    if port == Ps2Port::Aux {
+        receive_from_device(port)?;
+    }
+    send_to_device(port, DEVICE_ENABLE_SCANNING)
+}
+
+fn update_config(set: u8, clear: u8) -> Result<u8> {
+    let config = (read_config()? | set) & !clear;
+    write_config(config)?;
+    Ok(config)
+}
+
+// コントローラを自己診断してキーボードのポートを有効にする。
+// 割り込みはenable_irqで登録側が準備できてから有効にする。戻り値は設定バイト
+pub fn init() -> Result<u8> {
+    let _lock = CONTROLLER.lock();
+    write_command(CMD_DISABLE_KEYBOARD)?;
+    write_command(CMD_DISABLE_AUX)?;
+    flush_output();
+    let config = update_config(
+        0,
+        CONFIG_KEYBOARD_IRQ | CONFIG_AUX_IRQ | CONFIG_KEYBOARD_CLOCK_DISABLED,
+    )?;
+    write_command(CMD_SELF_TEST)?;
+    if read_response(TIMEOUT)? != SELF_TEST_PASSED {
+        return Err(Error::DeviceNotFound);
+    }
+    // 自己診断で設定が初期化されるコントローラもあるので、書き直しておく
+    write_config(config)?;
+    write_command(CMD_TEST_KEYBOARD)?;
+    if read_response(TIMEOUT)? != PORT_TEST_PASSED {
+        return Err(Error::DeviceNotFound);
+    }
+    write_command(CMD_ENABLE_KEYBOARD)?;
+    reset_device(Ps2Port::Keyboard)?;
+    Ok(config)
+}
+
+// 補助ポートを検査して有効にする
+pub fn enable_aux_port() -> Result<()> {
+    let _lock = CONTROLLER.lock();
+    write_command(CMD_TEST_AUX)?;
+    if read_response(TIMEOUT)? != PORT_TEST_PASSED {
+        return Err(Error::DeviceNotFound);
+    }
+    write_command(CMD_ENABLE_AUX)?;
+    update_config(0, CONFIG_AUX_CLOCK_DISABLED)?;
+    Ok(())
+}
+
+pub fn enable_irq(port: Ps2Port) -> Result<()> {
+    let _lock = CONTROLLER.lock();
+    let irq = match port {
+        Ps2Port::Keyboard => CONFIG_KEYBOARD_IRQ,
+        Ps2Port::Aux => CONFIG_AUX_IRQ,
+    };
+    update_config(irq, 0)?;
+    Ok(())
+}
@@ -0,0 +1,420 @@
+use crate::mutex::Mutex;
+use crate::result::Error;
+use crate::result::Result;
+use crate::x86::gdt::IST_DOUBLE_FAULT;
+use crate::x86::gdt::IST_MACHINE_CHECK;
+use crate::x86::gdt::IST_NMI;
+use crate::x86::gdt::KERNEL_CS;
+use crate::x86::read_cr2;
+use crate::x86::serial::SerialPort;
+use crate::x86::serial::COM1;
+use core::arch::asm;
+use core::arch::global_asm;
+use core::fmt;
+use core::fmt::Write;
+use core::mem::size_of;
+use core::ptr::addr_of;
+use core::ptr::addr_of_mut;
+use core::sync::atomic::AtomicU64;
+use core::sync::atomic::AtomicUsize;
+use core::sync::atomic::Ordering;
+
+pub const NUM_EXCEPTIONS: usize = 32;
+pub const NUM_VECTORS: usize = 256;
+
+pub const VECTOR_DIVIDE_ERROR: u8 = 0;
+pub const VECTOR_NMI: u8 = 2;
+pub const VECTOR_BREAKPOINT: u8 = 3;
+pub const VECTOR_DOUBLE_FAULT: u8 = 8;
+pub const VECTOR_GENERAL_PROTECTION: u8 = 13;
+pub const VECTOR_PAGE_FAULT: u8 = 14;
+pub const VECTOR_MACHINE_CHECK: u8 = 18;
+
+const EXCEPTION_NAMES: [(&str, &str); NUM_EXCEPTIONS] = [
+    ("#DE", "Divide Error"),
+    ("#DB", "Debug"),
+    ("NMI", "Non-Maskable Interrupt"),
+    ("#BP", "Breakpoint"),
+    ("#OF", "Overflow"),
+    ("#BR", "BOUND Range Exceeded"),
+    ("#UD", "Invalid Opcode"),
+    ("#NM", "Device Not Available"),
+    ("#DF", "Double Fault"),
+    ("", "Coprocessor Segment Overrun"),
+    ("#TS", "Invalid TSS"),
+    ("#NP", "Segment Not Present"),
+    ("#SS", "Stack-Segment Fault"),
+    ("#GP", "General Protection"),
+    ("#PF", "Page Fault"),
+    ("", "Reserved"),
+    ("#MF", "x87 Floating-Point Error"),
+    ("#AC", "Alignment Check"),
+    ("#MC", "Machine Check"),
+    ("#XM", "SIMD Floating-Point"),
+    ("#VE", "Virtualization"),
+    ("#CP", "Control Protection"),
+    ("", "Reserved"),
+    ("", "Reserved"),
+    ("", "Reserved"),
+    ("", "Reserved"),
+    ("", "Reserved"),
+    ("", "Reserved"),
+    ("#HV", "Hypervisor Injection"),
+    ("#VC", "VMM Communication"),
+    ("#SX", "Security"),
+    ("", "Reserved"),
+];
+
+// ベクタごとの入口は16バイトずつ並んでいて、CPUがエラーコードを積まないベクタでは
+// ダミーの0を積んでから、ベクタ番号を積んで共通の処理に飛ぶ。
+// 0x60227d00は、エラーコードを伴う例外 (8, 10-14, 17, 21, 29, 30) のビットマスク
+global_asm!(
+    ".align 16",
+    ".global interrupt_entries",
+    "interrupt_entries:",
+    ".set interrupt_vector, 0",
+    ".rept 256",
+    ".align 16",
+    ".if interrupt_vector >= 32 || ((0x60227d00 >> interrupt_vector) & 1) == 0",
+    "push 0",
+    ".endif",
+    "push interrupt_vector",
+    "jmp interrupt_common",
+    ".set interrupt_vector, interrupt_vector + 1",
+    ".endr",
+    "interrupt_common:",
+    "push rax",
+    "push rbx",
+    "push rcx",
+    "push rdx",
+    "push rsi",
+    "push rdi",
+    "push rbp",
+    "push r8",
+    "push r9",
+    "push r10",
+    "push r11",
+    "push r12",
+    "push r13",
+    "push r14",
+    "push r15",
+    // ここでRSPは16バイト境界に揃っている
+    "mov rdi, rsp",
+    "cld",
+    "call {handler}",
+    "pop r15",
+    "pop r14",
+    "pop r13",
+    "pop r12",
+    "pop r11",
+    "pop r10",
+    "pop r9",
+    "pop r8",
+    "pop rbp",
+    "pop rdi",
+    "pop rsi",
+    "pop rdx",
+    "pop rcx",
+    "pop rbx",
+    "pop rax",
+    // ベクタ番号とエラーコードを捨てる
+    "add rsp, 16",
+    "iretq",
+    handler = sym handle_interrupt,
+);
+const INTERRUPT_ENTRY_SIZE: usize = 16;
+
+extern "C" {
+    fn interrupt_entries();
+}
+
+// 入口で積んだレジスタと、CPUが積んだ割り込みフレーム (低いアドレスから順に)
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct InterruptFrame {
+    pub r15: u64,
+    pub r14: u64,
+    pub r13: u64,
+    pub r12: u64,
+    pub r11: u64,
+    pub r10: u64,
+    pub r9: u64,
+    pub r8: u64,
+    pub rbp: u64,
+    pub rdi: u64,
+    pub rsi: u64,
+    pub rdx: u64,
+    pub rcx: u64,
+    pub rbx: u64,
+    pub rax: u64,
+    pub vector: u64,
+    pub error_code: u64,
+    pub rip: u64,
+    pub cs: u64,
+    pub rflags: u64,
+    pub rsp: u64,
+    pub ss: u64,
+}
+
+// 例外の種類とすべてのレジスタを、画面にも収まる幅で書き出す
+struct ExceptionReport<'a> {
+    frame: &'a InterruptFrame,
+    cr2: u64,
+}
+
+impl fmt::Display for ExceptionReport<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let e = self.frame;
+        let (mnemonic, name) = EXCEPTION_NAMES[e.vector as usize % NUM_EXCEPTIONS];
+        writeln!(
+            f,
+            "{mnemonic} {name} (vector {}, error code {:#x})",
+            e.vector, e.error_code
+        )?;
+        let registers = [
+            ("RIP", e.rip),
+            ("RSP", e.rsp),
+            ("RFLAGS", e.rflags),
+            ("CR2", self.cr2),
+            ("CS", e.cs),
+            ("SS", e.ss),
+            ("RAX", e.rax),
+            ("RBX", e.rbx),
+            ("RCX", e.rcx),
+            ("RDX", e.rdx),
+            ("RSI", e.rsi),
+            ("RDI", e.rdi),
+            ("RBP", e.rbp),
+            ("R8", e.r8),
+            ("R9", e.r9),
+            ("R10", e.r10),
+            ("R11", e.r11),
+            ("R12", e.r12),
+            ("R13", e.r13),
+            ("R14", e.r14),
+            ("R15", e.r15),
+        ];
+        for (i, (name, value)) in registers.iter().enumerate() {
+            write!(f, "{name:>6} {value:#018x}")?;
+            if i % 3 == 2 {
+                writeln!(f)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[allow(clippy::declare_interior_mutable_const)]
+const ZERO_COUNT: AtomicUsize = AtomicUsize::new(0);
+static EXCEPTION_COUNTS: [AtomicUsize; NUM_EXCEPTIONS] = [ZERO_COUNT; NUM_EXCEPTIONS];
+static LAST_ERROR_CODE: AtomicU64 = AtomicU64::new(0);
+// 0でなければ、次の例外から戻るときにこのアドレスで実行を再開する
+static RESUME_RIP: AtomicU64 = AtomicU64::new(0);
+
+// 例外以外のベクタに登録するハンドラ
+pub type InterruptHandler = fn(&mut InterruptFrame);
+
+static HANDLERS: Mutex<[Option<InterruptHandler>; NUM_VECTORS]> =
+    Mutex::new([None; NUM_VECTORS]);
+
+// 例外以外のベクタにハンドラを登録する。例外の処理は差し替えられない
+pub fn set_handler(vector: u8, handler: InterruptHandler) -> Result<()> {
+    if (vector as usize) < NUM_EXCEPTIONS {
+        return Err(Error::InvalidParameter);
+    }
+    HANDLERS.lock()[vector as usize] = Some(handler);
+    Ok(())
+}
+
+extern "C" fn handle_interrupt(frame: &mut InterruptFrame) {
+    let vector = frame.vector as usize;
+    if vector < NUM_EXCEPTIONS {
+        return handle_exception(frame);
+    }
+    // ハンドラの中から登録し直せるよう、ロックを外してから呼ぶ
+    let handler = HANDLERS.lock()[vector];
+    match handler {
+        Some(handler) => handler(frame),
+        None => panic!("Unexpected interrupt (vector {vector:#x})"),
+    }
+}
+
+fn handle_exception(frame: &mut InterruptFrame) {
+    let cr2 = read_cr2();
+    let vector = frame.vector as usize;
+    EXCEPTION_COUNTS[vector].fetch_add(1, Ordering::SeqCst);
+    LAST_ERROR_CODE.store(frame.error_code, Ordering::SeqCst);
+    let resume_rip = RESUME_RIP.swap(0, Ordering::SeqCst);
+    if resume_rip != 0 {
+        frame.rip = resume_rip;
+        return;
+    }
+    let report = ExceptionReport { frame, cr2 };
+    if vector == VECTOR_BREAKPOINT as usize {
+        // ブレークポイントはトラップなので、報告して次の命令から続ける
+        let mut serial = SerialPort::new(COM1);
+        let _ = writeln!(serial, "{report}");
+        return;
+    }
+    panic!("{report}");
+}
+
+// 例外が起きた回数
+pub fn exception_count(vector: u8) -> usize {
+    EXCEPTION_COUNTS[vector as usize].load(Ordering::SeqCst)
+}
+
+pub fn last_error_code() -> u64 {
+    LAST_ERROR_CODE.load(Ordering::SeqCst)
+}
+
+const GATE_TYPE_INTERRUPT: u8 = 0xe;
+const GATE_DPL_USER: u8 = 3 << 5;
+const GATE_PRESENT: u8 = 1 << 7;
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct GateDescriptor {
+    offset_low: u16,
+    selector: u16,
+    ist: u8,
+    attributes: u8,
+    offset_mid: u16,
+    offset_high: u32,
+    _reserved: u32,
+}
+const _: () = assert!(size_of::<GateDescriptor>() == 16);
+
+impl GateDescriptor {
+    const fn empty() -> Self {
+        Self {
+            offset_low: 0,
+            selector: 0,
+            ist: 0,
+            attributes: 0,
+            offset_mid: 0,
+            offset_high: 0,
+            _reserved: 0,
+        }
+    }
+    fn new(handler: u64, ist: u8, attributes: u8) -> Self {
+        Self {
+            offset_low: handler as u16,
+            selector: KERNEL_CS,
+            ist,
+            attributes: GATE_PRESENT | GATE_TYPE_INTERRUPT | attributes,
+            offset_mid: (handler >> 16) as u16,
+            offset_high: (handler >> 32) as u32,
+            _reserved: 0,
+        }
+    }
+}
+
+#[repr(C, packed)]
+struct DescriptorTablePointer {
+    limit: u16,
+    base: u64,
+}
+
+#[repr(C, align(16))]
+struct InterruptDescriptorTable {
+    gates: [GateDescriptor; NUM_VECTORS],
+}
+
+static mut IDT: InterruptDescriptorTable = InterruptDescriptorTable {
+    gates: [GateDescriptor::empty(); NUM_VECTORS],
+};
+
+// すべてのベクタの入口を登録したIDTを読み込む。例外以外はset_handlerで処理を登録する。
+// 二重フォルトやNMI、マシンチェックは、スタックが壊れていても動けるようISTで受ける
+pub fn init() {
+    let entries = interrupt_entries as usize as u64;
+    unsafe {
+        let idt = &mut *addr_of_mut!(IDT);
+        for (vector, gate) in idt.gates.iter_mut().enumerate() {
+            let handler = entries + (vector * INTERRUPT_ENTRY_SIZE) as u64;
+            let ist = match vector as u8 {
+                VECTOR_NMI => IST_NMI,
+                VECTOR_DOUBLE_FAULT => IST_DOUBLE_FAULT,
+                VECTOR_MACHINE_CHECK => IST_MACHINE_CHECK,
+                _ => 0,
+            };
+            // int3はユーザーモードからも使えるようにする
+            let attributes = if vector as u8 == VECTOR_BREAKPOINT {
+                GATE_DPL_USER
+            } else {
+                0
+            };
+            *gate = GateDescriptor::new(handler, ist, attributes);
+        }
+        let pointer = DescriptorTablePointer {
+            limit: (size_of::<InterruptDescriptorTable>() - 1) as u16,
+            base: addr_of!(IDT) as u64,
+        };
+        asm!("lidt [{}]", in(reg) &pointer, options(readonly, nostack, preserves_flags));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::exception_count;
+    use super::last_error_code;
+    use super::RESUME_RIP;
+    use super::VECTOR_BREAKPOINT;
+    use super::VECTOR_DIVIDE_ERROR;
+    use super::VECTOR_PAGE_FAULT;
+    use crate::x86::read_cr2;
+    use core::arch::asm;
+
+    // 4レベルページングで表せる下半分の最後のページ。ファームウェアはここまでマップしない
+    const UNMAPPED_ADDR: u64 = 0x0000_7fff_ffff_f000;
+    const PF_ERROR_PRESENT: u64 = 1 << 0;
+
+    #[test_case]
+    fn breakpoint_returns_to_next_instruction() {
+        let count = exception_count(VECTOR_BREAKPOINT);
+        unsafe { asm!("int3") };
+        assert_eq!(exception_count(VECTOR_BREAKPOINT), count + 1);
+    }
+
+    #[test_case]
+    fn divide_error_is_caught() {
+        let count = exception_count(VECTOR_DIVIDE_ERROR);
+        unsafe {
+            asm!(
+                "lea {tmp}, [rip + 2f]",
+                "mov [{resume}], {tmp}",
+                "xor edx, edx",
+                "xor ecx, ecx",
+                "mov eax, 1",
+                "div ecx",
+                "2:",
+                resume = in(reg) RESUME_RIP.as_ptr(),
+                tmp = out(reg) _,
+                out("eax") _,
+                out("ecx") _,
+                out("edx") _,
+            );
+        }
+        assert_eq!(exception_count(VECTOR_DIVIDE_ERROR), count + 1);
+    }
+
+    #[test_case]
+    fn page_fault_reports_faulting_address() {
+        let count = exception_count(VECTOR_PAGE_FAULT);
+        unsafe {
+            asm!(
+                "lea {tmp}, [rip + 2f]",
+                "mov [{resume}], {tmp}",
+                "mov {tmp}, [{addr}]",
+                "2:",
+                resume = in(reg) RESUME_RIP.as_ptr(),
+                addr = in(reg) UNMAPPED_ADDR,
+                tmp = out(reg) _,
+            );
+        }
+        assert_eq!(exception_count(VECTOR_PAGE_FAULT), count + 1);
+        assert_eq!(read_cr2(), UNMAPPED_ADDR);
+        assert_eq!(last_error_code() & PF_ERROR_PRESENT, 0);
+    }
+}
@@ -0,0 +1,656 @@
+use crate::mutex::Mutex;
+use crate::queue::EventQueue;
+use crate::result::Result;
+use crate::x86::apic;
+use crate::x86::i8042;
+use crate::x86::i8042::Ps2Port;
+use crate::x86::i8042::CONFIG_TRANSLATION;
+use core::ops::BitOr;
+
+const KEYBOARD_IRQ: u8 = 1;
+const KEY_EVENT_QUEUE_SIZE: usize = 64;
+
+// 以下の接頭バイトは、スキャンコードの意味を変えるだけでキーを表さない
+const PREFIX_EXTENDED: u8 = 0xe0;
+const PREFIX_PAUSE: u8 = 0xe1;
+const SET2_PREFIX_RELEASE: u8 = 0xf0;
+const SET1_RELEASE: u8 = 0x80;
+// Pauseキーは押したときだけ、接頭バイトに続けて長い列を送ってくる
+const SET1_PAUSE_LENGTH: u8 = 5;
+const SET2_PAUSE_LENGTH: u8 = 7;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ScancodeSet {
+    Set1,
+    Set2,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyCode {
+    Escape,
+    F1,
+    F2,
+    F3,
+    F4,
+    F5,
+    F6,
+    F7,
+    F8,
+    F9,
+    F10,
+    F11,
+    F12,
+    Backquote,
+    Digit1,
+    Digit2,
+    Digit3,
+    Digit4,
+    Digit5,
+    Digit6,
+    Digit7,
+    Digit8,
+    Digit9,
+    Digit0,
+    Minus,
+    Equal,
+    Backspace,
+    Tab,
+    Q,
+    W,
+    E,
+    R,
+    T,
+    Y,
+    U,
+    I,
+    O,
+    P,
+    LeftBracket,
+    RightBracket,
+    Backslash,
+    CapsLock,
+    A,
+    S,
+    D,
+    F,
+    G,
+    H,
+    J,
+    K,
+    L,
+    Semicolon,
+    Quote,
+    Enter,
+    LeftShift,
+    Z,
+    X,
+    C,
+    V,
+    B,
+    N,
+    M,
+    Comma,
+    Period,
+    Slash,
+    RightShift,
+    LeftCtrl,
+    LeftAlt,
+    Space,
+    RightAlt,
+    RightCtrl,
+    NumLock,
+    ScrollLock,
+    Insert,
+    Delete,
+    Home,
+    End,
+    PageUp,
+    PageDown,
+    Up,
+    Down,
+    Left,
+    Right,
+}
+
+impl KeyCode {
+    // USレイアウトで、シフトなしとシフトありのときの文字
+    fn us_chars(self) -> Option<(char, char)> {
+        use KeyCode::*;
+        let chars = match self {
+            Backquote => ('`', '~'),
+            Digit1 => ('1', '!'),
+            Digit2 => ('2', '@'),
+            Digit3 => ('3', '#'),
+            Digit4 => ('4', '$'),
+            Digit5 => ('5', '%'),
+            Digit6 => ('6', '^'),
+            Digit7 => ('7', '&'),
+            Digit8 => ('8', '*'),
+            Digit9 => ('9', '('),
+            Digit0 => ('0', ')'),
+            Minus => ('-', '_'),
+            Equal => ('=', '+'),
+            Backspace => ('\x08', '\x08'),
+            Tab => ('\t', '\t'),
+            Q => ('q', 'Q'),
+            W => ('w', 'W'),
+            E => ('e', 'E'),
+            R => ('r', 'R'),
+            T => ('t', 'T'),
+            Y => ('y', 'Y'),
+            U => ('u', 'U'),
+            I => ('i', 'I'),
+            O => ('o', 'O'),
+            P => ('p', 'P'),
+            LeftBracket => ('[', '{'),
+            RightBracket => (']', '}'),
+            Backslash => ('\\', '|'),
+            A => ('a', 'A'),
+            S => ('s', 'S'),
+            D => ('d', 'D'),
+            F => ('f', 'F'),
+            G => ('g', 'G'),
+            H => ('h', 'H'),
+            J => ('j', 'J'),
+            K => ('k', 'K'),
+            L => ('l', 'L'),
+            Semicolon => (';', ':'),
+            Quote => ('\'', '"'),
+            Enter => ('\n', '\n'),
+            Z => ('z', 'Z'),
+            X => ('x', 'X'),
+            C => ('c', 'C'),
+            V => ('v', 'V'),
+            B => ('b', 'B'),
+            N => ('n', 'N'),
+            M => ('m', 'M'),
+            Comma => (',', '<'),
+            Period => ('.', '>'),
+            Slash => ('/', '?'),
+            Space => (' ', ' '),
+            Escape => ('\x1b', '\x1b'),
+            _ => return None,
+        };
+        Some(chars)
+    }
+
+    // 接頭バイト0xe0がついていれば、extendedがtrueになる
+    fn from_set1(code: u8, extended: bool) -> Option<Self> {
+        use KeyCode::*;
+        let key = match (extended, code) {
+            (false, 0x01) => Escape,
+            (false, 0x02) => Digit1,
+            (false, 0x03) => Digit2,
+            (false, 0x04) => Digit3,
+            (false, 0x05) => Digit4,
+            (false, 0x06) => Digit5,
+            (false, 0x07) => Digit6,
+            (false, 0x08) => Digit7,
+            (false, 0x09) => Digit8,
+            (false, 0x0a) => Digit9,
+            (false, 0x0b) => Digit0,
+            (false, 0x0c) => Minus,
+            (false, 0x0d) => Equal,
+            (false, 0x0e) => Backspace,
+            (false, 0x0f) => Tab,
+            (false, 0x10) => Q,
+            (false, 0x11) => W,
+            (false, 0x12) => E,
+            (false, 0x13) => R,
+            (false, 0x14) => T,
+            (false, 0x15) => Y,
+            (false, 0x16) => U,
+            (false, 0x17) => I,
+            (false, 0x18) => O,
+            (false, 0x19) => P,
+            (false, 0x1a) => LeftBracket,
+            (false, 0x1b) => RightBracket,
+            (_, 0x1c) => Enter,
+            (false, 0x1d) => LeftCtrl,
+            (false, 0x1e) => A,
+            (false, 0x1f) => S,
+            (false, 0x20) => D,
+            (false, 0x21) => F,
+            (false, 0x22) => G,
+            (false, 0x23) => H,
+            (false, 0x24) => J,
+            (false, 0x25) => K,
+            (false, 0x26) => L,
+            (false, 0x27) => Semicolon,
+            (false, 0x28) => Quote,
+            (false, 0x29) => Backquote,
+            (false, 0x2a) => LeftShift,
+            (false, 0x2b) => Backslash,
+            (false, 0x2c) => Z,
+            (false, 0x2d) => X,
+            (false, 0x2e) => C,
+            (false, 0x2f) => V,
+            (false, 0x30) => B,
+            (false, 0x31) => N,
+            (false, 0x32) => M,
+            (false, 0x33) => Comma,
+            (false, 0x34) => Period,
+            (_, 0x35) => Slash,
+            (false, 0x36) => RightShift,
+            (false, 0x38) => LeftAlt,
+            (false, 0x39) => Space,
+            (false, 0x3a) => CapsLock,
+            (false, 0x3b) => F1,
+            (false, 0x3c) => F2,
+            (false, 0x3d) => F3,
+            (false, 0x3e) => F4,
+            (false, 0x3f) => F5,
+            (false, 0x40) => F6,
+            (false, 0x41) => F7,
+            (false, 0x42) => F8,
+            (false, 0x43) => F9,
+            (false, 0x44) => F10,
+            (false, 0x45) => NumLock,
+            (false, 0x46) => ScrollLock,
+            (false, 0x57) => F11,
+            (false, 0x58) => F12,
+            (true, 0x1d) => RightCtrl,
+            (true, 0x38) => RightAlt,
+            (true, 0x47) => Home,
+            (true, 0x48) => Up,
+            (true, 0x49) => PageUp,
+            (true, 0x4b) => Left,
+            (true, 0x4d) => Right,
+            (true, 0x4f) => End,
+            (true, 0x50) => Down,
+            (true, 0x51) => PageDown,
+            (true, 0x52) => Insert,
+            (true, 0x53) => Delete,
+            _ => return None,
+        };
+        Some(key)
+    }
+
+    fn from_set2(code: u8, extended: bool) -> Option<Self> {
+        use KeyCode::*;
+        let key = match (extended, code) {
+            (false, 0x76) => Escape,
+            (false, 0x05) => F1,
+            (false, 0x06) => F2,
+            (false, 0x04) => F3,
+            (false, 0x0c) => F4,
+            (false, 0x03) => F5,
+            (false, 0x0b) => F6,
+            (false, 0x83) => F7,
+            (false, 0x0a) => F8,
+            (false, 0x01) => F9,
+            (false, 0x09) => F10,
+            (false, 0x78) => F11,
+            (false, 0x07) => F12,
+            (false, 0x0e) => Backquote,
+            (false, 0x16) => Digit1,
+            (false, 0x1e) => Digit2,
+            (false, 0x26) => Digit3,
+            (false, 0x25) => Digit4,
+            (false, 0x2e) => Digit5,
+            (false, 0x36) => Digit6,
+            (false, 0x3d) => Digit7,
+            (false, 0x3e) => Digit8,
+            (false, 0x46) => Digit9,
+            (false, 0x45) => Digit0,
+            (false, 0x4e) => Minus,
+            (false, 0x55) => Equal,
+            (false, 0x66) => Backspace,
+            (false, 0x0d) => Tab,
+            (false, 0x15) => Q,
+            (false, 0x1d) => W,
+            (false, 0x24) => E,
+            (false, 0x2d) => R,
+            (false, 0x2c) => T,
+            (false, 0x35) => Y,
+            (false, 0x3c) => U,
+            (false, 0x43) => I,
+            (false, 0x44) => O,
+            (false, 0x4d) => P,
+            (false, 0x54) => LeftBracket,
+            (false, 0x5b) => RightBracket,
+            (false, 0x5d) => Backslash,
+            (false, 0x58) => CapsLock,
+            (false, 0x1c) => A,
+            (false, 0x1b) => S,
+            (false, 0x23) => D,
+            (false, 0x2b) => F,
+            (false, 0x34) => G,
+            (false, 0x33) => H,
+            (false, 0x3b) => J,
+            (false, 0x42) => K,
+            (false, 0x4b) => L,
+            (false, 0x4c) => Semicolon,
+            (false, 0x52) => Quote,
+            (_, 0x5a) => Enter,
+            (false, 0x12) => LeftShift,
+            (false, 0x1a) => Z,
+            (false, 0x22) => X,
+            (false, 0x21) => C,
+            (false, 0x2a) => V,
+            (false, 0x32) => B,
+            (false, 0x31) => N,
+            (false, 0x3a) => M,
+            (false, 0x41) => Comma,
+            (false, 0x49) => Period,
+            (_, 0x4a) => Slash,
+            (false, 0x59) => RightShift,
+            (false, 0x14) => LeftCtrl,
+            (false, 0x11) => LeftAlt,
+            (false, 0x29) => Space,
+            (false, 0x77) => NumLock,
+            (false, 0x7e) => ScrollLock,
+            (true, 0x14) => RightCtrl,
+            (true, 0x11) => RightAlt,
+            (true, 0x70) => Insert,
+            (true, 0x71) => Delete,
+            (true, 0x6c) => Home,
+            (true, 0x69) => End,
+            (true, 0x7d) => PageUp,
+            (true, 0x7a) => PageDown,
+            (true, 0x75) => Up,
+            (true, 0x72) => Down,
+            (true, 0x6b) => Left,
+            (true, 0x74) => Right,
+            _ => return None,
+        };
+        Some(key)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Modifiers(u8);
+
+impl Modifiers {
+    pub const SHIFT: Modifiers = Modifiers(1 << 0);
+    pub const CTRL: Modifiers = Modifiers(1 << 1);
+    pub const ALT: Modifiers = Modifiers(1 << 2);
+    pub const CAPS_LOCK: Modifiers = Modifiers(1 << 3);
+
+    pub const fn empty() -> Self {
+        Modifiers(0)
+    }
+
+    pub const fn contains(&self, other: Modifiers) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl BitOr for Modifiers {
+    type Output = Modifiers;
+    fn bitor(self, rhs: Modifiers) -> Modifiers {
+        Modifiers(self.0 | rhs.0)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeyEvent {
+    pub key: KeyCode,
+    pub pressed: bool,
+    // このイベントを処理した後の修飾キーの状態
+    pub modifiers: Modifiers,
+    // 押したときに入力される文字。Ctrlと英字の組み合わせは制御文字になる
+    pub ch: Option<char>,
+}
+
+// 左右どちらかが押されていれば、その修飾キーが効いているとみなす
+#[derive(Default)]
+struct ModifierKeys {
+    left_shift: bool,
+    right_shift: bool,
+    left_ctrl: bool,
+    right_ctrl: bool,
+    left_alt: bool,
+    right_alt: bool,
+    caps_lock_held: bool,
+    caps_lock: bool,
+}
+
+impl ModifierKeys {
+    fn update(&mut self, key: KeyCode, pressed: bool) {
+        match key {
+            KeyCode::LeftShift => self.left_shift = pressed,
+            KeyCode::RightShift => self.right_shift = pressed,
+            KeyCode::LeftCtrl => self.left_ctrl = pressed,
+            KeyCode::RightCtrl => self.right_ctrl = pressed,
+            KeyCode::LeftAlt => self.left_alt = pressed,
+            KeyCode::RightAlt => self.right_alt = pressed,
+            KeyCode::CapsLock => {
+                // 押し続けたときのリピートでは切り替えない
+                if pressed && !self.caps_lock_held {
+                    self.caps_lock = !self.caps_lock;
+                }
+                self.caps_lock_held = pressed;
+            }
+            _ => {}
+        }
+    }
+
+    fn modifiers(&self) -> Modifiers {
+        let mut modifiers = Modifiers::empty();
+        for (is_active, flag) in [
+            (self.left_shift || self.right_shift, Modifiers::SHIFT),
+            (self.left_ctrl || self.right_ctrl, Modifiers::CTRL),
+            (self.left_alt || self.right_alt, Modifiers::ALT),
+            (self.caps_lock, Modifiers::CAPS_LOCK),
+        ] {
+            if is_active {
+                modifiers = modifiers | flag;
+            }
+        }
+        modifiers
+    }
+}
+
+fn key_to_char(key: KeyCode, modifiers: Modifiers) -> Option<char> {
+    let (normal, shifted) = key.us_chars()?;
+    let is_letter = normal.is_ascii_lowercase();
+    // CapsLockは英字にだけ効き、Shiftと同時なら打ち消し合う
+    let shift = modifiers.contains(Modifiers::SHIFT)
+        ^ (is_letter && modifiers.contains(Modifiers::CAPS_LOCK));
+    if modifiers.contains(Modifiers::CTRL) {
+        return is_letter.then_some((normal as u8 & 0x1f) as char);
+    }
+    Some(if shift { shifted } else { normal })
+}
+
+// キーボードから届くバイト列を、1バイトずつキーイベントに変換する
+pub struct ScancodeDecoder {
+    set: ScancodeSet,
+    extended: bool,
+    released: bool,
+    bytes_to_skip: u8,
+    modifier_keys: ModifierKeys,
+}
+
+impl ScancodeDecoder {
+    pub fn new(set: ScancodeSet) -> Self {
+        Self {
+            set,
+            extended: false,
+            released: false,
+            bytes_to_skip: 0,
+            modifier_keys: ModifierKeys::default(),
+        }
+    }
+
+    pub fn set(&self) -> ScancodeSet {
+        self.set
+    }
+
+    pub fn feed(&mut self, byte: u8) -> Option<KeyEvent> {
+        if self.bytes_to_skip > 0 {
+            self.bytes_to_skip -= 1;
+            return None;
+        }
+        let (code, released) = match (self.set, byte) {
+            (_, PREFIX_EXTENDED) => {
+                self.extended = true;
+                return None;
+            }
+            (ScancodeSet::Set1, PREFIX_PAUSE) => {
+                self.bytes_to_skip = SET1_PAUSE_LENGTH;
+                return None;
+            }
+            (ScancodeSet::Set2, PREFIX_PAUSE) => {
+                self.bytes_to_skip = SET2_PAUSE_LENGTH;
+                return None;
+            }
+            (ScancodeSet::Set2, SET2_PREFIX_RELEASE) => {
+                self.released = true;
+                return None;
+            }
+            (ScancodeSet::Set1, byte) => (byte & !SET1_RELEASE, byte & SET1_RELEASE != 0),
+            (ScancodeSet::Set2, byte) => (byte, self.released),
+        };
+        let extended = self.extended;
+        self.extended = false;
+        self.released = false;
+        // ACKやエラーの応答、PrintScreenの前後に送られる偽のShiftなどは捨てる
+        let key = match self.set {
+            ScancodeSet::Set1 => KeyCode::from_set1(code, extended),
+            ScancodeSet::Set2 => KeyCode::from_set2(code, extended),
+        }?;
+        let pressed = !released;
+        self.modifier_keys.update(key, pressed);
+        let modifiers = self.modifier_keys.modifiers();
+        Some(KeyEvent {
+            key,
+            pressed,
+            modifiers,
+            ch: if pressed {
+                key_to_char(key, modifiers)
+            } else {
+                None
+            },
+        })
+    }
+}
+
+static DECODER: Mutex<Option<ScancodeDecoder>> = Mutex::new(None);
+static KEY_EVENTS: Mutex<EventQueue<KeyEvent, KEY_EVENT_QUEUE_SIZE>> =
+    Mutex::new(EventQueue::new());
+
+fn handle_keyboard_irq() {
+    let Some(byte) = i8042::read_port_data(Ps2Port::Keyboard) else {
+        return;
+    };
+    let event = DECODER
+        .lock()
+        .as_mut()
+        .and_then(|decoder| decoder.feed(byte));
+    if let Some(event) = event {
+        KEY_EVENTS.lock().push(event);
+    }
+}
+
+// i8042を初期化し、IRQ1でキーイベントを受け取り始める。
+// コントローラが変換を有効にしていれば、セット2はセット1に変換されて届く
+pub fn init() -> Result<()> {
+    let config = i8042::init()?;
+    let set = if config & CONFIG_TRANSLATION != 0 {
+        ScancodeSet::Set1
+    } else {
+        ScancodeSet::Set2
+    };
+    *DECODER.lock() = Some(ScancodeDecoder::new(set));
+    apic::set_irq_handler(KEYBOARD_IRQ, handle_keyboard_irq)?;
+    i8042::enable_irq(Ps2Port::Keyboard)
+}
+
+// 初期化に成功していれば、キーボードが送ってくるスキャンコードセット
+pub fn scancode_set() -> Option<ScancodeSet> {
+    DECODER.lock().as_ref().map(|decoder| decoder.set())
+}
+
+pub fn read_event() -> Option<KeyEvent> {
+    KEY_EVENTS.lock().pop()
+}
+
+// 文字が入力されるまでのイベントを読み捨てる。文字がなくなればNone
+pub fn read_char() -> Option<char> {
+    while let Some(event) = read_event() {
+        if let Some(ch) = event.ch {
+            return Some(ch);
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod test {
+    use super::scancode_set;
+    use super::KeyCode;
+    use super::KeyEvent;
+    use super::Modifiers;
+    use super::ScancodeDecoder;
+    use super::ScancodeSet;
+    use alloc::string::String;
+    use alloc::vec::Vec;
+
+    fn decode(set: ScancodeSet, bytes: &[u8]) -> Vec<KeyEvent> {
+        let mut decoder = ScancodeDecoder::new(set);
+        bytes.iter().filter_map(|b| decoder.feed(*b)).collect()
+    }
+
+    fn typed(set: ScancodeSet, bytes: &[u8]) -> String {
+        decode(set, bytes).iter().filter_map(|e| e.ch).collect()
+    }
+
+    #[test_case]
+    fn set1_is_decoded_with_shift_and_caps_lock() {
+        // a, Shift+a, 1, Shift+1
+        let bytes = [0x1e, 0x9e, 0x2a, 0x1e, 0x9e, 0xaa, 0x02, 0x82, 0x36, 0x02];
+        assert_eq!(typed(ScancodeSet::Set1, &bytes), "aA1!");
+        // CapsLockは押し続けても1回だけ切り替わり、Shiftと打ち消し合う
+        let bytes = [0x3a, 0x3a, 0xba, 0x1e, 0x2a, 0x1e, 0x02];
+        assert_eq!(typed(ScancodeSet::Set1, &bytes), "Aa!");
+    }
+
+    #[test_case]
+    fn set2_is_decoded_with_release_and_extended_keys() {
+        let events = decode(
+            ScancodeSet::Set2,
+            &[0x1c, 0xf0, 0x1c, 0xe0, 0x75, 0xe0, 0xf0, 0x75],
+        );
+        let keys: Vec<(KeyCode, bool)> = events.iter().map(|e| (e.key, e.pressed)).collect();
+        assert_eq!(
+            keys,
+            [
+                (KeyCode::A, true),
+                (KeyCode::A, false),
+                (KeyCode::Up, true),
+                (KeyCode::Up, false)
+            ]
+        );
+        assert_eq!(events[0].ch, Some('a'));
+        assert_eq!(events[1].ch, None);
+    }
+
+    #[test_case]
+    fn modifiers_are_tracked_per_side() {
+        // 右Ctrl+c、左Altを押したまま右Ctrlを離す
+        let events = decode(
+            ScancodeSet::Set2,
+            &[0xe0, 0x14, 0x21, 0x11, 0xe0, 0xf0, 0x14],
+        );
+        assert_eq!(events[1].ch, Some('\x03'));
+        assert_eq!(events[2].modifiers, Modifiers::CTRL | Modifiers::ALT);
+        assert_eq!(events[3].modifiers, Modifiers::ALT);
+    }
+
+    #[test_case]
+    fn pause_and_fake_shift_sequences_are_ignored() {
+        let set1 = [
+            0xe1, 0x1d, 0x45, 0xe1, 0x9d, 0xc5, 0xe0, 0x2a, 0xe0, 0xaa, 0x1e,
+        ];
+        assert_eq!(typed(ScancodeSet::Set1, &set1), "a");
+        let set2 = [0xe1, 0x14, 0x77, 0xe1, 0xf0, 0x14, 0xf0, 0x77, 0xfa, 0x1c];
+        assert_eq!(typed(ScancodeSet::Set2, &set2), "a");
+    }
+
+    #[test_case]
+    fn keyboard_is_initialized_at_boot() {
+        assert!(scancode_set().is_some());
+    }
+}
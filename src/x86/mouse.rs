@@ -0,0 +1,233 @@
+use crate::mutex::Mutex;
+use crate::queue::EventQueue;
+use crate::result::Result;
+use crate::x86::apic;
+use crate::x86::i8042;
+use crate::x86::i8042::Ps2Port;
+
+const MOUSE_IRQ: u8 = 12;
+const MOUSE_EVENT_QUEUE_SIZE: usize = 64;
+
+const DEVICE_SET_SAMPLE_RATE: u8 = 0xf3;
+const DEVICE_GET_ID: u8 = 0xf2;
+// この順にサンプリングレートを設定すると、IntelliMouseはホイールを有効にしてIDを3に変える
+const INTELLIMOUSE_SAMPLE_RATES: [u8; 3] = [200, 100, 80];
+const DEVICE_ID_INTELLIMOUSE: u8 = 3;
+const DEFAULT_SAMPLE_RATE: u8 = 100;
+
+const PACKET_SIZE: usize = 3;
+const INTELLIMOUSE_PACKET_SIZE: usize = 4;
+// パケットの先頭バイト
+const PACKET_LEFT_BUTTON: u8 = 1 << 0;
+const PACKET_RIGHT_BUTTON: u8 = 1 << 1;
+const PACKET_MIDDLE_BUTTON: u8 = 1 << 2;
+const PACKET_ALWAYS_ONE: u8 = 1 << 3;
+const PACKET_X_SIGN: u8 = 1 << 4;
+const PACKET_Y_SIGN: u8 = 1 << 5;
+const PACKET_X_OVERFLOW: u8 = 1 << 6;
+const PACKET_Y_OVERFLOW: u8 = 1 << 7;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct MouseButtons(u8);
+
+impl MouseButtons {
+    pub const LEFT: MouseButtons = MouseButtons(PACKET_LEFT_BUTTON);
+    pub const RIGHT: MouseButtons = MouseButtons(PACKET_RIGHT_BUTTON);
+    pub const MIDDLE: MouseButtons = MouseButtons(PACKET_MIDDLE_BUTTON);
+
+    pub const fn empty() -> Self {
+        MouseButtons(0)
+    }
+
+    pub const fn contains(&self, other: MouseButtons) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+// 1パケット分の相対移動とボタンの状態。dyは画面に合わせて下向きを正とする
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MouseEvent {
+    pub dx: i32,
+    pub dy: i32,
+    // ホイールの回転量。手前に回すと正になる。3バイトのパケットでは常に0
+    pub wheel: i32,
+    pub buttons: MouseButtons,
+}
+
+// 9ビットの符号付き整数。上位ビットは先頭バイトにある
+fn sign_extend_9bit(value: u8, is_negative: bool) -> i32 {
+    if is_negative {
+        value as i32 - 0x100
+    } else {
+        value as i32
+    }
+}
+
+// 下位4ビットの符号付き整数
+fn sign_extend_4bit(value: u8) -> i32 {
+    ((value << 4) as i8 >> 4) as i32
+}
+
+// マウスから届くバイト列を、パケットにまとめてイベントに変換する
+pub struct PacketDecoder {
+    packet_size: usize,
+    bytes: [u8; INTELLIMOUSE_PACKET_SIZE],
+    len: usize,
+}
+
+impl PacketDecoder {
+    pub fn new(has_wheel: bool) -> Self {
+        Self {
+            packet_size: if has_wheel {
+                INTELLIMOUSE_PACKET_SIZE
+            } else {
+                PACKET_SIZE
+            },
+            bytes: [0; INTELLIMOUSE_PACKET_SIZE],
+            len: 0,
+        }
+    }
+
+    pub fn has_wheel(&self) -> bool {
+        self.packet_size == INTELLIMOUSE_PACKET_SIZE
+    }
+
+    pub fn feed(&mut self, byte: u8) -> Option<MouseEvent> {
+        // 先頭バイトの常に1のビットが立っていなければ、パケットの途中から読んでいるので捨てる
+        if self.len == 0 && byte & PACKET_ALWAYS_ONE == 0 {
+            return None;
+        }
+        self.bytes[self.len] = byte;
+        self.len += 1;
+        if self.len < self.packet_size {
+            return None;
+        }
+        self.len = 0;
+        let [flags, x, y, extra] = self.bytes;
+        // あふれたときの移動量は当てにならないので捨てる
+        let dx = if flags & PACKET_X_OVERFLOW != 0 {
+            0
+        } else {
+            sign_extend_9bit(x, flags & PACKET_X_SIGN != 0)
+        };
+        let dy = if flags & PACKET_Y_OVERFLOW != 0 {
+            0
+        } else {
+            -sign_extend_9bit(y, flags & PACKET_Y_SIGN != 0)
+        };
+        let wheel = if self.has_wheel() {
+            sign_extend_4bit(extra)
+        } else {
+            0
+        };
+        Some(MouseEvent {
+            dx,
+            dy,
+            wheel,
+            buttons: MouseButtons(
+                flags & (PACKET_LEFT_BUTTON | PACKET_RIGHT_BUTTON | PACKET_MIDDLE_BUTTON),
+            ),
+        })
+    }
+}
+
+static DECODER: Mutex<Option<PacketDecoder>> = Mutex::new(None);
+static MOUSE_EVENTS: Mutex<EventQueue<MouseEvent, MOUSE_EVENT_QUEUE_SIZE>> =
+    Mutex::new(EventQueue::new());
+
+fn handle_mouse_irq() {
+    let Some(byte) = i8042::read_port_data(Ps2Port::Aux) else {
+        return;
+    };
+    let event = DECODER
+        .lock()
+        .as_mut()
+        .and_then(|decoder| decoder.feed(byte));
+    if let Some(event) = event {
+        MOUSE_EVENTS.lock().push(event);
+    }
+}
+
+fn set_sample_rate(rate: u8) -> Result<()> {
+    i8042::send_to_device(Ps2Port::Aux, DEVICE_SET_SAMPLE_RATE)?;
+    i8042::send_to_device(Ps2Port::Aux, rate)
+}
+
+// ホイールを有効にできれば、4バイトのパケットを送ってくるようになる
+fn enable_wheel() -> Result<bool> {
+    for rate in INTELLIMOUSE_SAMPLE_RATES {
+        set_sample_rate(rate)?;
+    }
+    i8042::send_to_device(Ps2Port::Aux, DEVICE_GET_ID)?;
+    let id = i8042::receive_from_device(Ps2Port::Aux)?;
+    set_sample_rate(DEFAULT_SAMPLE_RATE)?;
+    Ok(id == DEVICE_ID_INTELLIMOUSE)
+}
+
+// i8042の補助ポートにつながったマウスを初期化し、IRQ12でイベントを受け取り始める。
+// キーボードの初期化でコントローラを初期化した後に呼ぶ
+pub fn init() -> Result<()> {
+    i8042::enable_aux_port()?;
+    i8042::reset_device(Ps2Port::Aux)?;
+    let has_wheel = enable_wheel()?;
+    *DECODER.lock() = Some(PacketDecoder::new(has_wheel));
+    apic::set_irq_handler(MOUSE_IRQ, handle_mouse_irq)?;
+    i8042::enable_irq(Ps2Port::Aux)
+}
+
+// 初期化に成功していれば、ホイールつきのマウスかどうか
+pub fn has_wheel() -> Option<bool> {
+    DECODER.lock().as_ref().map(|decoder| decoder.has_wheel())
+}
+
+pub fn read_event() -> Option<MouseEvent> {
+    MOUSE_EVENTS.lock().pop()
+}
+
+#[cfg(test)]
+mod test {
+    use super::has_wheel;
+    use super::MouseButtons;
+    use super::MouseEvent;
+    use super::PacketDecoder;
+    use alloc::vec::Vec;
+
+    fn decode(has_wheel: bool, bytes: &[u8]) -> Vec<MouseEvent> {
+        let mut decoder = PacketDecoder::new(has_wheel);
+        bytes.iter().filter_map(|b| decoder.feed(*b)).collect()
+    }
+
+    #[test_case]
+    fn three_byte_packets_are_decoded() {
+        // 左ボタンを押して右上へ、右ボタンを押して左下へ動かす
+        let events = decode(false, &[0x09, 5, 3, 0x3a, 0xfe, 0xfc]);
+        assert_eq!(events.len(), 2);
+        assert_eq!((events[0].dx, events[0].dy, events[0].wheel), (5, -3, 0));
+        assert_eq!(events[0].buttons, MouseButtons::LEFT);
+        assert_eq!((events[1].dx, events[1].dy), (-2, 4));
+        assert!(events[1].buttons.contains(MouseButtons::RIGHT));
+        assert!(!events[1].buttons.contains(MouseButtons::LEFT));
+    }
+
+    #[test_case]
+    fn four_byte_packets_carry_wheel_motion() {
+        let events = decode(true, &[0x0c, 0, 0, 0x0f, 0x08, 1, 0, 0x01]);
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0].wheel, -1);
+        assert_eq!(events[0].buttons, MouseButtons::MIDDLE);
+        assert_eq!((events[1].dx, events[1].wheel), (1, 1));
+    }
+
+    #[test_case]
+    fn decoder_resynchronizes_and_drops_overflowed_motion() {
+        // 先頭バイトに見えないバイトは読み飛ばす
+        let events = decode(false, &[0x00, 0x05, 0xc8, 0xff, 0xff]);
+        assert_eq!(events.len(), 1);
+        assert_eq!((events[0].dx, events[0].dy), (0, 0));
+    }
+
+    #[test_case]
+    fn mouse_is_initialized_at_boot() {
+        assert!(has_wheel().is_some());
+    }
+}
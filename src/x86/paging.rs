@@ -0,0 +1,553 @@
+use crate::frame_allocator::FRAME_ALLOCATOR;
+use crate::frame_allocator::FRAME_SIZE;
+use crate::mutex::Mutex;
+use crate::result::Error;
+use crate::result::Result;
+use crate::uefi::MemoryMapHolder;
+use crate::x86::invlpg;
+use crate::x86::read_cr0;
+use crate::x86::read_cr3;
+use crate::x86::read_msr;
+use crate::x86::write_cr0;
+use crate::x86::write_cr3;
+use crate::x86::write_msr;
+use crate::x86::CR0_WP;
+use crate::x86::EFER_NXE;
+use crate::x86::MSR_EFER;
+use core::arch::x86_64::__cpuid;
+use core::cmp::max;
+use core::fmt;
+use core::ops::BitOr;
+use core::sync::atomic::AtomicBool;
+use core::sync::atomic::Ordering;
+
+const ENTRIES_PER_TABLE: usize = 512;
+const ADDR_MASK: u64 = 0x000f_ffff_ffff_f000;
+const PML4_LEVEL: usize = 4;
+// ファームウェアのマッピングに合わせ、少なくとも最初の4GiBは恒等マップする
+const MIN_IDENTITY_MAP_END: usize = 4 << 30;
+
+#[derive(Clone, Copy, PartialEq, Eq, Default)]
+#[repr(transparent)]
+pub struct PageFlags(u64);
+
+impl PageFlags {
+    pub const PRESENT: PageFlags = PageFlags(1 << 0);
+    pub const WRITABLE: PageFlags = PageFlags(1 << 1);
+    pub const USER: PageFlags = PageFlags(1 << 2);
+    pub const WRITE_THROUGH: PageFlags = PageFlags(1 << 3);
+    pub const CACHE_DISABLE: PageFlags = PageFlags(1 << 4);
+    pub const ACCESSED: PageFlags = PageFlags(1 << 5);
+    pub const DIRTY: PageFlags = PageFlags(1 << 6);
+    pub const HUGE_PAGE: PageFlags = PageFlags(1 << 7);
+    pub const GLOBAL: PageFlags = PageFlags(1 << 8);
+    pub const NO_EXECUTE: PageFlags = PageFlags(1 << 63);
+
+    const NAMES: [(PageFlags, &'static str); 10] = [
+        (PageFlags::PRESENT, "P"),
+        (PageFlags::WRITABLE, "W"),
+        (PageFlags::USER, "U"),
+        (PageFlags::WRITE_THROUGH, "PWT"),
+        (PageFlags::CACHE_DISABLE, "PCD"),
+        (PageFlags::ACCESSED, "A"),
+        (PageFlags::DIRTY, "D"),
+        (PageFlags::HUGE_PAGE, "PS"),
+        (PageFlags::GLOBAL, "G"),
+        (PageFlags::NO_EXECUTE, "NX"),
+    ];
+
+    pub const fn empty() -> Self {
+        PageFlags(0)
+    }
+
+    pub const fn bits(&self) -> u64 {
+        self.0
+    }
+
+    const fn from_entry(entry: u64) -> Self {
+        PageFlags(entry & !ADDR_MASK)
+    }
+
+    pub const fn contains(&self, other: PageFlags) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    const fn without(self, other: PageFlags) -> Self {
+        PageFlags(self.0 & !other.0)
+    }
+}
+
+impl BitOr for PageFlags {
+    type Output = PageFlags;
+    fn bitor(self, rhs: PageFlags) -> PageFlags {
+        PageFlags(self.0 | rhs.0)
+    }
+}
+
+// "P|W|NX" のように出力する
+impl fmt::Display for PageFlags {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut first = true;
+        for (flag, name) in PageFlags::NAMES.iter() {
+            if self.contains(*flag) {
+                if !first {
+                    write!(f, "|")?;
+                }
+                write!(f, "{name}")?;
+                first = false;
+            }
+        }
+        if first {
+            write!(f, "-")?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Debug for PageFlags {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "PageFlags({self})")
+    }
+}
+
+// PATは電源投入時の設定 (0: WB, 1: WT, 2: UC-, 3: UC) のまま使う
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CacheMode {
+    WriteBack,
+    WriteThrough,
+    UncachedMinus,
+    Uncached,
+}
+
+impl CacheMode {
+    pub const fn flags(self) -> PageFlags {
+        match self {
+            CacheMode::WriteBack => PageFlags::empty(),
+            CacheMode::WriteThrough => PageFlags::WRITE_THROUGH,
+            CacheMode::UncachedMinus => PageFlags::CACHE_DISABLE,
+            CacheMode::Uncached => PageFlags(
+                PageFlags::WRITE_THROUGH.bits() | PageFlags::CACHE_DISABLE.bits(),
+            ),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PageSize {
+    Size4K,
+    Size2M,
+    Size1G,
+}
+
+impl PageSize {
+    pub const fn bytes(self) -> usize {
+        match self {
+            PageSize::Size4K => 4 << 10,
+            PageSize::Size2M => 2 << 20,
+            PageSize::Size1G => 1 << 30,
+        }
+    }
+    // このサイズのページを指すエントリが置かれるテーブルの段 (PTが1、PML4が4)
+    const fn level(self) -> usize {
+        match self {
+            PageSize::Size4K => 1,
+            PageSize::Size2M => 2,
+            PageSize::Size1G => 3,
+        }
+    }
+}
+
+#[repr(C, align(4096))]
+struct PageTable {
+    entries: [u64; ENTRIES_PER_TABLE],
+}
+
+fn table_index(virt: usize, level: usize) -> usize {
+    (virt >> (12 + 9 * (level - 1))) % ENTRIES_PER_TABLE
+}
+
+fn level_size(level: usize) -> usize {
+    1 << (12 + 9 * (level - 1))
+}
+
+// 48ビットの仮想アドレスで、上位ビットがビット47の符号拡張になっているか
+fn is_canonical(virt: usize) -> bool {
+    let upper = virt >> 47;
+    upper == 0 || upper == (1 << 17) - 1
+}
+
+pub fn supports_1g_pages() -> bool {
+    let extended = unsafe { __cpuid(0x8000_0001) };
+    extended.edx & (1 << 26) != 0
+}
+
+fn supports_nx() -> bool {
+    let extended = unsafe { __cpuid(0x8000_0001) };
+    extended.edx & (1 << 20) != 0
+}
+
+// EFER.NXEが立っていないと、NXビットは予約ビット違反のページフォルトになる
+static NX_ENABLED: AtomicBool = AtomicBool::new(false);
+
+// ページテーブル自体は、物理アドレスと同じ仮想アドレスでアクセスする。
+// そのため、どのアドレス空間でも物理メモリの恒等マップを残しておく必要がある
+pub struct AddressSpace {
+    pml4: *mut PageTable,
+}
+
+unsafe impl Send for AddressSpace {}
+
+impl AddressSpace {
+    pub fn new() -> Result<Self> {
+        Ok(Self {
+            pml4: alloc_table()?,
+        })
+    }
+
+    pub fn pml4_addr(&self) -> usize {
+        self.pml4 as usize
+    }
+
+    pub fn is_active(&self) -> bool {
+        read_cr3() & ADDR_MASK == self.pml4_addr() as u64
+    }
+
+    pub fn map(&mut self, virt: usize, phys: usize, size: PageSize, flags: PageFlags) -> Result<()> {
+        if virt % size.bytes() != 0 || phys % size.bytes() != 0 {
+            return Err(Error::InvalidParameter);
+        }
+        if !is_canonical(virt) || phys as u64 & !ADDR_MASK != 0 {
+            return Err(Error::OutOfRange);
+        }
+        if size == PageSize::Size1G && !supports_1g_pages() {
+            return Err(Error::Unsupported);
+        }
+        let mut flags = flags | PageFlags::PRESENT;
+        if size != PageSize::Size4K {
+            flags = flags | PageFlags::HUGE_PAGE;
+        }
+        if !NX_ENABLED.load(Ordering::Relaxed) {
+            flags = flags.without(PageFlags::NO_EXECUTE);
+        }
+        let table = self.walk(virt, size.level(), true)?;
+        let entry = unsafe { &mut (*table).entries[table_index(virt, size.level())] };
+        if *entry & PageFlags::PRESENT.bits() != 0 {
+            return Err(Error::AlreadyMapped);
+        }
+        *entry = phys as u64 | flags.bits();
+        Ok(())
+    }
+
+    // 対応付けを外し、ページが指していた物理アドレスを返す
+    pub fn unmap(&mut self, virt: usize, size: PageSize) -> Result<usize> {
+        if virt % size.bytes() != 0 {
+            return Err(Error::InvalidParameter);
+        }
+        let table = self.walk(virt, size.level(), false)?;
+        let entry = unsafe { &mut (*table).entries[table_index(virt, size.level())] };
+        let flags = PageFlags::from_entry(*entry);
+        let is_huge = size != PageSize::Size4K;
+        if !flags.contains(PageFlags::PRESENT) || flags.contains(PageFlags::HUGE_PAGE) != is_huge {
+            return Err(Error::NotMapped);
+        }
+        let phys = (*entry & ADDR_MASK) as usize;
+        *entry = 0;
+        if self.is_active() {
+            invlpg(virt);
+        }
+        Ok(phys)
+    }
+
+    // 仮想アドレスを物理アドレスに変換する
+    pub fn translate(&self, virt: usize) -> Option<usize> {
+        self.translate_with_flags(virt).map(|(phys, _)| phys)
+    }
+
+    pub fn translate_with_flags(&self, virt: usize) -> Option<(usize, PageFlags)> {
+        if !is_canonical(virt) {
+            return None;
+        }
+        let mut table = self.pml4;
+        for level in (1..=PML4_LEVEL).rev() {
+            let entry = unsafe { (*table).entries[table_index(virt, level)] };
+            let flags = PageFlags::from_entry(entry);
+            if !flags.contains(PageFlags::PRESENT) {
+                return None;
+            }
+            let addr = (entry & ADDR_MASK) as usize;
+            if level == 1 || flags.contains(PageFlags::HUGE_PAGE) {
+                let offset = virt % level_size(level);
+                // 大きなページでは、ビット12はPATを表すのでアドレスから除く
+                let base = addr & !(level_size(level) - 1);
+                return Some((base + offset, flags));
+            }
+            table = addr as *mut PageTable;
+        }
+        None
+    }
+
+    // [start, end) を、揃っている範囲ではできるだけ大きなページで恒等マップする
+    pub fn identity_map(&mut self, start: usize, end: usize, flags: PageFlags) -> Result<()> {
+        if start % FRAME_SIZE != 0 || end % FRAME_SIZE != 0 {
+            return Err(Error::InvalidParameter);
+        }
+        let use_1g_pages = supports_1g_pages();
+        let mut addr = start;
+        while addr < end {
+            let size = [PageSize::Size1G, PageSize::Size2M, PageSize::Size4K]
+                .into_iter()
+                .filter(|s| *s != PageSize::Size1G || use_1g_pages)
+                .find(|s| addr % s.bytes() == 0 && end - addr >= s.bytes())
+                .unwrap();
+            self.map(addr, addr, size, flags)?;
+            addr += size.bytes();
+        }
+        Ok(())
+    }
+
+    // [start, end) を4KiBのページで恒等マップし直す。大きなページに含まれていれば、
+    // 同じ対応付けのまま分割してから、範囲内のページだけをflagsにする
+    pub fn remap_identity(&mut self, start: usize, end: usize, flags: PageFlags) -> Result<()> {
+        if start % FRAME_SIZE != 0 || end % FRAME_SIZE != 0 {
+            return Err(Error::InvalidParameter);
+        }
+        for addr in (start..end).step_by(FRAME_SIZE) {
+            self.split_huge_pages(addr)?;
+            match self.unmap(addr, PageSize::Size4K) {
+                Ok(_) | Err(Error::NotMapped) => {}
+                Err(e) => return Err(e),
+            }
+            self.map(addr, addr, PageSize::Size4K, flags)?;
+        }
+        Ok(())
+    }
+
+    /// # Safety
+    ///
+    /// The address space must map the running code, stack, data and all page tables.
+    pub unsafe fn activate(&self) {
+        write_cr3(self.pml4_addr() as u64);
+    }
+
+    // virtを含むlevel段目のテーブルを返す。途中のテーブルがなければ、createのときだけ作る
+    fn walk(&mut self, virt: usize, level: usize, create: bool) -> Result<*mut PageTable> {
+        let mut table = self.pml4;
+        for l in (level + 1..=PML4_LEVEL).rev() {
+            let entry = unsafe { &mut (*table).entries[table_index(virt, l)] };
+            let flags = PageFlags::from_entry(*entry);
+            if flags.contains(PageFlags::PRESENT) {
+                // 大きなページの中は、作るときは対応付け済み、外すときはこの大きさでは未対応付けとみなす
+                if flags.contains(PageFlags::HUGE_PAGE) {
+                    return Err(if create {
+                        Error::AlreadyMapped
+                    } else {
+                        Error::NotMapped
+                    });
+                }
+            } else if create {
+                // 途中の段は緩くしておき、権限は末端のエントリで決める
+                let flags = PageFlags::PRESENT | PageFlags::WRITABLE | PageFlags::USER;
+                *entry = alloc_table()? as u64 | flags.bits();
+            } else {
+                return Err(Error::NotMapped);
+            }
+            table = (*entry & ADDR_MASK) as *mut PageTable;
+        }
+        Ok(table)
+    }
+}
+
+impl AddressSpace {
+    // virtを含む大きなページを、4KiBのページになるまで、同じ対応付けと属性の一段小さなページに分ける
+    fn split_huge_pages(&mut self, virt: usize) -> Result<()> {
+        let mut table = self.pml4;
+        for level in (2..=PML4_LEVEL).rev() {
+            let entry = unsafe { &mut (*table).entries[table_index(virt, level)] };
+            let flags = PageFlags::from_entry(*entry);
+            if !flags.contains(PageFlags::PRESENT) {
+                return Ok(());
+            }
+            if flags.contains(PageFlags::HUGE_PAGE) {
+                let base = (*entry & ADDR_MASK) as usize & !(level_size(level) - 1);
+                // 4KiBのページのエントリでは、ビット7はPATを表す
+                let child_flags = if level == 2 {
+                    flags.without(PageFlags::HUGE_PAGE)
+                } else {
+                    flags
+                };
+                let child = alloc_table()?;
+                for (i, e) in unsafe { (*child).entries.iter_mut() }.enumerate() {
+                    *e = (base + i * level_size(level - 1)) as u64 | child_flags.bits();
+                }
+                let table_flags = PageFlags::PRESENT | PageFlags::WRITABLE | PageFlags::USER;
+                *entry = child as u64 | table_flags.bits();
+            }
+            table = (*entry & ADDR_MASK) as *mut PageTable;
+        }
+        Ok(())
+    }
+}
+
+// ページテーブルだけを解放する。対応付けられていた物理フレームは持ち主が解放する
+impl Drop for AddressSpace {
+    fn drop(&mut self) {
+        assert!(!self.is_active(), "Active address space dropped");
+        unsafe { free_table(self.pml4, PML4_LEVEL) };
+    }
+}
+
+fn alloc_table() -> Result<*mut PageTable> {
+    let table = FRAME_ALLOCATOR.alloc_frame()? as *mut PageTable;
+    unsafe { table.write_bytes(0, 1) };
+    Ok(table)
+}
+
+unsafe fn free_table(table: *mut PageTable, level: usize) {
+    if level > 1 {
+        for entry in (*table).entries {
+            let flags = PageFlags::from_entry(entry);
+            if flags.contains(PageFlags::PRESENT) && !flags.contains(PageFlags::HUGE_PAGE) {
+                free_table((entry & ADDR_MASK) as *mut PageTable, level - 1);
+            }
+        }
+    }
+    let _ = FRAME_ALLOCATOR.free_frame(table as usize);
+}
+
+pub static KERNEL_ADDRESS_SPACE: Mutex<Option<AddressSpace>> = Mutex::new(None);
+
+// 物理メモリ全体を恒等マップした、カーネル自身のページテーブルに切り替える。
+// ファームウェアのページテーブルはBOOT_SERVICES_DATAにあって切り替えるまで使われているので、
+// 新しいテーブルは、BOOT_SERVICES_*を解放する前にCONVENTIONAL_MEMORYから確保する
+pub fn init(memory_map: &MemoryMapHolder) -> Result<()> {
+    assert!(
+        !FRAME_ALLOCATOR.is_boot_services_memory_released(),
+        "Page tables must be allocated before boot services memory is released"
+    );
+    if supports_nx() {
+        unsafe { write_msr(MSR_EFER, read_msr(MSR_EFER) | EFER_NXE) };
+        NX_ENABLED.store(true, Ordering::Relaxed);
+    }
+    // カーネルモードでも読み出し専用のページへの書き込みを禁止する
+    unsafe { write_cr0(read_cr0() | CR0_WP) };
+    let end = memory_map
+        .iter()
+        .map(|e| e.physical_end() as usize)
+        .fold(MIN_IDENTITY_MAP_END, max)
+        .next_multiple_of(PageSize::Size2M.bytes());
+    let mut address_space = AddressSpace::new()?;
+    address_space.identity_map(0, end, PageFlags::WRITABLE)?;
+    unsafe { address_space.activate() };
+    *KERNEL_ADDRESS_SPACE.lock() = Some(address_space);
+    Ok(())
+}
+
+// MMIOの範囲を、cache_modeでカーネルのアドレス空間に恒等マップする。
+// 物理メモリの恒等マップに含まれる範囲でも、その範囲のページだけ属性を変える
+pub fn map_mmio(addr: usize, size: usize, cache_mode: CacheMode) -> Result<()> {
+    let start = addr & !(FRAME_SIZE - 1);
+    let end = (addr + size).next_multiple_of(FRAME_SIZE);
+    let mut space = KERNEL_ADDRESS_SPACE.lock();
+    let space = space.as_mut().ok_or(Error::NotInitialized)?;
+    let flags = PageFlags::WRITABLE | PageFlags::NO_EXECUTE | cache_mode.flags();
+    space.remap_identity(start, end, flags)
+}
+
+#[cfg(test)]
+mod test {
+    use super::supports_1g_pages;
+    use super::AddressSpace;
+    use super::CacheMode;
+    use super::PageFlags;
+    use super::PageSize;
+    use super::KERNEL_ADDRESS_SPACE;
+    use crate::frame_allocator::FRAME_ALLOCATOR;
+    use crate::result::Error;
+
+    // 恒等マップの範囲から十分に離れた仮想アドレス
+    const TEST_VIRT: usize = 0x0000_4000_0000_0000;
+
+    #[test_case]
+    fn map_translate_and_unmap_4k_page() {
+        let frame = FRAME_ALLOCATOR.alloc_frame().unwrap();
+        let mut space = AddressSpace::new().unwrap();
+        let flags = PageFlags::WRITABLE | PageFlags::NO_EXECUTE;
+        space.map(TEST_VIRT, frame, PageSize::Size4K, flags).unwrap();
+        assert_eq!(space.translate(TEST_VIRT + 0x123), Some(frame + 0x123));
+        let (_, mapped_flags) = space.translate_with_flags(TEST_VIRT).unwrap();
+        assert!(mapped_flags.contains(PageFlags::PRESENT | PageFlags::WRITABLE));
+        assert!(!mapped_flags.contains(PageFlags::USER));
+        assert_eq!(
+            space.map(TEST_VIRT, frame, PageSize::Size4K, flags),
+            Err(Error::AlreadyMapped)
+        );
+        assert_eq!(space.unmap(TEST_VIRT, PageSize::Size4K), Ok(frame));
+        assert_eq!(space.translate(TEST_VIRT), None);
+        assert_eq!(space.unmap(TEST_VIRT, PageSize::Size4K), Err(Error::NotMapped));
+        drop(space);
+        FRAME_ALLOCATOR.free_frame(frame).unwrap();
+    }
+
+    #[test_case]
+    fn large_pages_translate_with_offset() {
+        let mut space = AddressSpace::new().unwrap();
+        let phys_2m = 0x4000_0000;
+        assert_eq!(
+            space.map(TEST_VIRT + 0x1000, phys_2m, PageSize::Size2M, PageFlags::empty()),
+            Err(Error::InvalidParameter)
+        );
+        space.map(TEST_VIRT, phys_2m, PageSize::Size2M, PageFlags::empty()).unwrap();
+        assert_eq!(space.translate(TEST_VIRT + 0x12345), Some(phys_2m + 0x12345));
+        // 大きなページの内側には、4KiBのページを置けない
+        assert_eq!(
+            space.map(TEST_VIRT + 0x1000, 0, PageSize::Size4K, PageFlags::empty()),
+            Err(Error::AlreadyMapped)
+        );
+        if supports_1g_pages() {
+            let virt_1g = TEST_VIRT + PageSize::Size1G.bytes();
+            space.map(virt_1g, 0, PageSize::Size1G, PageFlags::empty()).unwrap();
+            assert_eq!(space.translate(virt_1g + 0x1234_5678), Some(0x1234_5678));
+        }
+    }
+
+    #[test_case]
+    fn kernel_address_space_is_active() {
+        let mut space = KERNEL_ADDRESS_SPACE.lock();
+        let space = space.as_mut().expect("Paging is not initialized");
+        assert!(space.is_active());
+        // 恒等マップはそのまま使える
+        let frame = FRAME_ALLOCATOR.alloc_frame().unwrap();
+        assert_eq!(space.translate(frame), Some(frame));
+        // 別の仮想アドレスから同じフレームに書き込める
+        space.map(TEST_VIRT, frame, PageSize::Size4K, PageFlags::WRITABLE).unwrap();
+        unsafe {
+            (TEST_VIRT as *mut u64).write_volatile(0x1234_5678_9abc_def0);
+            assert_eq!((frame as *const u64).read_volatile(), 0x1234_5678_9abc_def0);
+        }
+        assert_eq!(space.unmap(TEST_VIRT, PageSize::Size4K), Ok(frame));
+        FRAME_ALLOCATOR.free_frame(frame).unwrap();
+    }
+
+    #[test_case]
+    fn remap_identity_splits_large_page() {
+        let mut space = AddressSpace::new().unwrap();
+        let phys_2m = 0x4000_0000;
+        space
+            .identity_map(phys_2m, phys_2m + PageSize::Size2M.bytes(), PageFlags::WRITABLE)
+            .unwrap();
+        let page = phys_2m + 0x10_0000;
+        let uncached = PageFlags::WRITABLE | CacheMode::Uncached.flags();
+        space.remap_identity(page, page + 0x1000, uncached).unwrap();
+        let (phys, flags) = space.translate_with_flags(page + 0x123).unwrap();
+        assert_eq!(phys, page + 0x123);
+        assert!(flags.contains(uncached));
+        assert!(!flags.contains(PageFlags::HUGE_PAGE));
+        // 分割した残りのページは、元の対応付けと属性のまま
+        let (phys, flags) = space.translate_with_flags(page + 0x1000).unwrap();
+        assert_eq!(phys, page + 0x1000);
+        assert!(flags.contains(PageFlags::WRITABLE));
+        assert!(!flags.contains(PageFlags::CACHE_DISABLE));
+        assert_eq!(space.translate(phys_2m), Some(phys_2m));
+        assert_eq!(space.unmap(phys_2m, PageSize::Size2M), Err(Error::NotMapped));
+    }
+}
@@ -0,0 +1,225 @@
+use core::arch::asm;
+use core::marker::PhantomData;
+
+// I/Oポートで読み書きできる幅 (u8, u16, u32) の値
+pub trait PortValue: Copy {
+    fn read_from_port(port: u16) -> Self;
+    fn write_to_port(port: u16, value: Self);
+    // 同じポートから、bufの長さだけ続けて読む (rep ins)
+    fn read_slice_from_port(port: u16, buf: &mut [Self]);
+    // 同じポートに、bufの内容を続けて書く (rep outs)
+    fn write_slice_to_port(port: u16, buf: &[Self]);
+}
+
+impl PortValue for u8 {
+    fn read_from_port(port: u16) -> Self {
+        let value: u8;
+        unsafe {
+            asm!(
+                "in al, dx",
+                in("dx") port,
+                out("al") value,
+            );
+        }
+        value
+    }
+
+    fn write_to_port(port: u16, value: Self) {
+        unsafe {
+            asm!(
+                "out dx, al",
+                in("dx") port,
+                in("al") value,
+            );
+        }
+    }
+
+    fn read_slice_from_port(port: u16, buf: &mut [Self]) {
+        unsafe {
+            asm!(
+                "rep insb",
+                in("dx") port,
+                inout("rdi") buf.as_mut_ptr() => _,
+                inout("rcx") buf.len() => _,
+            );
+        }
+    }
+
+    fn write_slice_to_port(port: u16, buf: &[Self]) {
+        unsafe {
+            asm!(
+                "rep outsb",
+                in("dx") port,
+                inout("rsi") buf.as_ptr() => _,
+                inout("rcx") buf.len() => _,
+            );
+        }
+    }
+}
+
+impl PortValue for u16 {
+    fn read_from_port(port: u16) -> Self {
+        let value: u16;
+        unsafe {
+            asm!(
+                "in ax, dx",
+                in("dx") port,
+                out("ax") value,
+            );
+        }
+        value
+    }
+
+    fn write_to_port(port: u16, value: Self) {
+        unsafe {
+            asm!(
+                "out dx, ax",
+                in("dx") port,
+                in("ax") value,
+            );
+        }
+    }
+
+    fn read_slice_from_port(port: u16, buf: &mut [Self]) {
+        unsafe {
+            asm!(
+                "rep insw",
+                in("dx") port,
+                inout("rdi") buf.as_mut_ptr() => _,
+                inout("rcx") buf.len() => _,
+            );
+        }
+    }
+
+    fn write_slice_to_port(port: u16, buf: &[Self]) {
+        unsafe {
+            asm!(
+                "rep outsw",
+                in("dx") port,
+                inout("rsi") buf.as_ptr() => _,
+                inout("rcx") buf.len() => _,
+            );
+        }
+    }
+}
+
+impl PortValue for u32 {
+    fn read_from_port(port: u16) -> Self {
+        let value: u32;
+        unsafe {
+            asm!(
+                "in eax, dx",
+                in("dx") port,
+                out("eax") value,
+            );
+        }
+        value
+    }
+
+    fn write_to_port(port: u16, value: Self) {
+        unsafe {
+            asm!(
+                "out dx, eax",
+                in("dx") port,
+                in("eax") value,
+            );
+        }
+    }
+
+    fn read_slice_from_port(port: u16, buf: &mut [Self]) {
+        unsafe {
+            asm!(
+                "rep insd",
+                in("dx") port,
+                inout("rdi") buf.as_mut_ptr() => _,
+                inout("rcx") buf.len() => _,
+            );
+        }
+    }
+
+    fn write_slice_to_port(port: u16, buf: &[Self]) {
+        unsafe {
+            asm!(
+                "rep outsd",
+                in("dx") port,
+                inout("rsi") buf.as_ptr() => _,
+                inout("rcx") buf.len() => _,
+            );
+        }
+    }
+}
+
+// 幅Tで読み書きするI/Oポート。ATAのデータポートならPort<u16>のように使う
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Port<T> {
+    port: u16,
+    _value: PhantomData<T>,
+}
+
+impl<T: PortValue> Port<T> {
+    pub const fn new(port: u16) -> Self {
+        Self {
+            port,
+            _value: PhantomData,
+        }
+    }
+
+    pub const fn port(&self) -> u16 {
+        self.port
+    }
+
+    // offsetだけ離れた、同じ幅のポート。デバイスのレジスタを基底からの位置で指すのに使う
+    pub const fn offset(&self, offset: u16) -> Self {
+        Self::new(self.port + offset)
+    }
+
+    pub fn read(&self) -> T {
+        T::read_from_port(self.port)
+    }
+
+    pub fn write(&self, value: T) {
+        T::write_to_port(self.port, value)
+    }
+
+    pub fn read_slice(&self, buf: &mut [T]) {
+        T::read_slice_from_port(self.port, buf)
+    }
+
+    pub fn write_slice(&self, buf: &[T]) {
+        T::write_slice_to_port(self.port, buf)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Port;
+
+    const PCI_CONFIG_ADDRESS: Port<u32> = Port::new(0xcf8);
+    const PCI_CONFIG_DATA: Port<u32> = Port::new(0xcfc);
+    // バス0、デバイス0、ファンクション0 (ホストブリッジ) のベンダIDとデバイスID
+    const PCI_HOST_BRIDGE_ID: u32 = 0x8000_0000;
+    // POSTコードの出力先。書いても何も起きない
+    const POST_CODE: Port<u8> = Port::new(0x80);
+
+    #[test_case]
+    fn ports_are_accessed_in_all_widths() {
+        PCI_CONFIG_ADDRESS.write(PCI_HOST_BRIDGE_ID);
+        assert_eq!(PCI_CONFIG_ADDRESS.read(), PCI_HOST_BRIDGE_ID);
+        let id = PCI_CONFIG_DATA.read();
+        assert_ne!(id, 0xffff_ffff);
+        let vendor_id = Port::<u16>::new(PCI_CONFIG_DATA.port()).read();
+        assert_eq!(vendor_id, id as u16);
+        let low_byte = Port::<u8>::new(PCI_CONFIG_DATA.port()).read();
+        assert_eq!(low_byte, id as u8);
+    }
+
+    #[test_case]
+    fn string_io_repeats_the_same_port() {
+        PCI_CONFIG_ADDRESS.write(PCI_HOST_BRIDGE_ID);
+        let vendor_id = Port::<u16>::new(PCI_CONFIG_DATA.port());
+        let mut buf = [0u16; 8];
+        vendor_id.read_slice(&mut buf);
+        assert!(buf.iter().all(|e| *e == vendor_id.read()));
+        POST_CODE.write_slice(&[0x12, 0x34, 0x56]);
+    }
+}
@@ -0,0 +1,137 @@
+use crate::acpi;
+use crate::result::Result;
+use crate::time::DateTime;
+use crate::x86::port::Port;
+use core::hint::spin_loop;
+
+const CMOS_ADDRESS: Port<u8> = Port::new(0x70);
+const CMOS_DATA: Port<u8> = Port::new(0x71);
+
+const REG_SECONDS: u8 = 0x00;
+const REG_MINUTES: u8 = 0x02;
+const REG_HOURS: u8 = 0x04;
+const REG_DAY_OF_MONTH: u8 = 0x07;
+const REG_MONTH: u8 = 0x08;
+const REG_YEAR: u8 = 0x09;
+const REG_STATUS_A: u8 = 0x0a;
+const REG_STATUS_B: u8 = 0x0b;
+const STATUS_A_UPDATE_IN_PROGRESS: u8 = 1 << 7;
+const STATUS_B_24_HOUR: u8 = 1 << 1;
+const STATUS_B_BINARY: u8 = 1 << 2;
+const HOUR_PM: u8 = 1 << 7;
+// FADTの本体で、世紀を保持するCMOSレジスタの番号が置かれている位置
+const FADT_CENTURY_OFFSET: usize = 108 - 36;
+
+fn read_cmos(reg: u8) -> u8 {
+    CMOS_ADDRESS.write(reg);
+    CMOS_DATA.read()
+}
+
+// 更新中でないときに読んだ生の値 (秒, 分, 時, 日, 月, 年, 世紀)
+fn read_registers(century_reg: Option<u8>) -> [u8; 7] {
+    while read_cmos(REG_STATUS_A) & STATUS_A_UPDATE_IN_PROGRESS != 0 {
+        spin_loop();
+    }
+    [
+        read_cmos(REG_SECONDS),
+        read_cmos(REG_MINUTES),
+        read_cmos(REG_HOURS),
+        read_cmos(REG_DAY_OF_MONTH),
+        read_cmos(REG_MONTH),
+        read_cmos(REG_YEAR),
+        century_reg.map_or(0, read_cmos),
+    ]
+}
+
+fn bcd_to_binary(value: u8) -> u8 {
+    (value >> 4) * 10 + (value & 0x0f)
+}
+
+// 12時間制では、最上位ビットが午後を表し、0時は12時と書かれる
+fn decode_hour(raw: u8, is_binary: bool, is_24_hour: bool) -> u8 {
+    let is_pm = !is_24_hour && raw & HOUR_PM != 0;
+    let raw = if is_24_hour { raw } else { raw & !HOUR_PM };
+    let hour = if is_binary { raw } else { bcd_to_binary(raw) };
+    match (is_24_hour, is_pm) {
+        (true, _) => hour,
+        (false, false) => hour % 12,
+        (false, true) => hour % 12 + 12,
+    }
+}
+
+fn decode(raw: [u8; 7], status_b: u8, has_century: bool) -> Result<DateTime> {
+    let is_binary = status_b & STATUS_B_BINARY != 0;
+    let is_24_hour = status_b & STATUS_B_24_HOUR != 0;
+    let decode_value = |value| if is_binary { value } else { bcd_to_binary(value) };
+    let [second, minute, hour, day, month, year, century] = raw;
+    // 世紀のレジスタがなければ、2000年代とみなす
+    let century = if has_century { decode_value(century) } else { 20 };
+    DateTime::new(
+        century as u16 * 100 + decode_value(year) as u16,
+        decode_value(month),
+        decode_value(day),
+        decode_hour(hour, is_binary, is_24_hour),
+        decode_value(minute),
+        decode_value(second),
+    )
+}
+
+fn century_register() -> Option<u8> {
+    let fadt = acpi::find_table(b"FACP")?;
+    match fadt.body().get(FADT_CENTURY_OFFSET) {
+        Some(0) | None => None,
+        Some(reg) => Some(*reg),
+    }
+}
+
+// RTCの日時を読む。RTCはUTCで動いているものとする。
+// 読んでいる途中で更新されることがあるので、2回続けて同じ値になるまで読み直す
+pub fn read_time() -> Result<DateTime> {
+    let century_reg = century_register();
+    let mut raw = read_registers(century_reg);
+    loop {
+        let again = read_registers(century_reg);
+        if again == raw {
+            break;
+        }
+        raw = again;
+    }
+    decode(raw, read_cmos(REG_STATUS_B), century_reg.is_some())
+}
+
+#[cfg(test)]
+mod test {
+    use super::decode;
+    use super::decode_hour;
+    use super::read_time;
+    use super::STATUS_B_24_HOUR;
+    use super::STATUS_B_BINARY;
+    use crate::time::now_utc;
+    use crate::time::DateTime;
+
+    #[test_case]
+    fn hours_are_decoded_in_all_formats() {
+        assert_eq!(decode_hour(0x23, false, true), 23);
+        assert_eq!(decode_hour(23, true, true), 23);
+        assert_eq!(decode_hour(0x12, false, false), 0);
+        assert_eq!(decode_hour(0x92, false, false), 12);
+        assert_eq!(decode_hour(0x81, false, false), 13);
+        assert_eq!(decode_hour(0x80 | 11, true, false), 23);
+    }
+
+    #[test_case]
+    fn bcd_and_binary_registers_are_decoded() {
+        let expected = DateTime::new(2024, 12, 31, 23, 59, 58).unwrap();
+        let bcd = [0x58, 0x59, 0x23, 0x31, 0x12, 0x24, 0x20];
+        assert_eq!(decode(bcd, STATUS_B_24_HOUR, true), Ok(expected));
+        let binary = [58, 59, 0x80 | 11, 31, 12, 24, 0];
+        assert_eq!(decode(binary, STATUS_B_BINARY, false), Ok(expected));
+    }
+
+    #[test_case]
+    fn rtc_agrees_with_wall_clock() {
+        let rtc = read_time().unwrap().unix_timestamp();
+        let wall_clock = now_utc().unwrap().unix_timestamp();
+        assert!(rtc.abs_diff(wall_clock) <= 2);
+    }
+}
@@ -0,0 +1,109 @@
+use crate::result::Error;
+use crate::result::Result;
+use crate::x86::read_io_port_u8;
+use crate::x86::write_io_port_u8;
+use core::fmt;
+
+pub const COM1: u16 = 0x3f8;
+pub const COM2: u16 = 0x2f8;
+
+// 16550 UARTのクロックは1.8432MHzで、分周比1のとき115200bps
+const UART_BASE_BAUD: u32 = 115200;
+
+const REG_DATA: u16 = 0; // THR / RBR (DLAB = 0), DLL (DLAB = 1)
+const REG_INTERRUPT_ENABLE: u16 = 1; // IER (DLAB = 0), DLM (DLAB = 1)
+const REG_FIFO_CONTROL: u16 = 2;
+const REG_LINE_CONTROL: u16 = 3;
+const REG_MODEM_CONTROL: u16 = 4;
+const REG_LINE_STATUS: u16 = 5;
+
+const LCR_8N1: u8 = 0x03;
+const LCR_DLAB: u8 = 0x80;
+const FCR_ENABLE_AND_CLEAR_14: u8 = 0xc7;
+const MCR_DTR_RTS_OUT1_OUT2: u8 = 0x0f;
+const MCR_LOOPBACK: u8 = 0x1e;
+const LSR_DATA_READY: u8 = 0x01;
+const LSR_THR_EMPTY: u8 = 0x20;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SerialPort {
+    base: u16,
+}
+
+impl SerialPort {
+    pub const fn new(base: u16) -> Self {
+        Self { base }
+    }
+
+    fn write_reg(&self, reg: u16, value: u8) {
+        write_io_port_u8(self.base + reg, value);
+    }
+
+    fn read_reg(&self, reg: u16) -> u8 {
+        read_io_port_u8(self.base + reg)
+    }
+
+    pub fn init(&mut self, baud: u32) -> Result<()> {
+        self.write_reg(REG_INTERRUPT_ENABLE, 0x00);
+        self.set_baud_rate(baud)?;
+        self.write_reg(REG_LINE_CONTROL, LCR_8N1);
+        self.write_reg(REG_FIFO_CONTROL, FCR_ENABLE_AND_CLEAR_14);
+        // ループバックモードで1バイト送って、UARTが存在するか確認する
+        self.write_reg(REG_MODEM_CONTROL, MCR_LOOPBACK);
+        self.write_reg(REG_DATA, 0xae);
+        if self.read_reg(REG_DATA) != 0xae {
+            return Err(Error::DeviceNotFound);
+        }
+        self.write_reg(REG_MODEM_CONTROL, MCR_DTR_RTS_OUT1_OUT2);
+        Ok(())
+    }
+
+    pub fn set_baud_rate(&mut self, baud: u32) -> Result<()> {
+        if baud == 0 || baud > UART_BASE_BAUD || UART_BASE_BAUD % baud != 0 {
+            return Err(Error::InvalidParameter);
+        }
+        let divisor = (UART_BASE_BAUD / baud) as u16;
+        let lcr = self.read_reg(REG_LINE_CONTROL);
+        self.write_reg(REG_LINE_CONTROL, lcr | LCR_DLAB);
+        self.write_reg(REG_DATA, divisor as u8);
+        self.write_reg(REG_INTERRUPT_ENABLE, (divisor >> 8) as u8);
+        self.write_reg(REG_LINE_CONTROL, lcr & !LCR_DLAB);
+        Ok(())
+    }
+
+    pub fn send_byte(&mut self, byte: u8) {
+        while self.read_reg(REG_LINE_STATUS) & LSR_THR_EMPTY == 0 {
+            core::hint::spin_loop();
+        }
+        self.write_reg(REG_DATA, byte);
+    }
+
+    pub fn try_receive_byte(&mut self) -> Option<u8> {
+        if self.read_reg(REG_LINE_STATUS) & LSR_DATA_READY == 0 {
+            None
+        } else {
+            Some(self.read_reg(REG_DATA))
+        }
+    }
+
+    pub fn receive_byte(&mut self) -> u8 {
+        loop {
+            if let Some(byte) = self.try_receive_byte() {
+                return byte;
+            }
+            core::hint::spin_loop();
+        }
+    }
+}
+
+impl fmt::Write for SerialPort {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for b in s.bytes() {
+            if b == b'\n' {
+                self.send_byte(b'\r');
+            }
+            self.send_byte(b);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,358 @@
+use crate::acpi;
+use crate::mutex::Mutex;
+use crate::result::Error;
+use crate::result::Result;
+use crate::x86::apic::end_of_interrupt;
+use crate::x86::apic::local_apic_read;
+use crate::x86::apic::local_apic_write;
+use crate::x86::apic::LAPIC_LVT_TIMER;
+use crate::x86::apic::LVT_MASKED;
+use crate::x86::are_interrupts_enabled;
+use crate::x86::hlt;
+use crate::x86::idt;
+use crate::x86::idt::InterruptFrame;
+use crate::x86::paging;
+use crate::x86::paging::CacheMode;
+use crate::x86::rdtsc;
+use crate::x86::read_io_port_u8;
+use crate::x86::write_io_port_u8;
+use core::hint::spin_loop;
+use core::ptr::read_volatile;
+use core::ptr::write_volatile;
+use core::sync::atomic::AtomicBool;
+use core::sync::atomic::AtomicU64;
+use core::sync::atomic::Ordering;
+use core::time::Duration;
+
+pub const TIMER_VECTOR: u8 = 0x30;
+pub const TICK_HZ: u32 = 100;
+const MAX_TICK_CALLBACKS: usize = 8;
+// 校正に使う時間。短すぎると誤差が大きくなる
+const CALIBRATION_MS: u64 = 10;
+const NANOS_PER_SEC: u128 = 1_000_000_000;
+
+const PIT_FREQUENCY_HZ: u64 = 1_193_182;
+const PIT_CHANNEL2_DATA: u16 = 0x42;
+const PIT_COMMAND: u16 = 0x43;
+// チャンネル2、下位・上位バイトの順に書く、モード0 (カウント終了で出力がHになる)
+const PIT_CHANNEL2_ONE_SHOT: u8 = 0b1011_0000;
+const PORT_SPEAKER_CONTROL: u16 = 0x61;
+const SPEAKER_GATE2: u8 = 1 << 0;
+const SPEAKER_DATA: u8 = 1 << 1;
+const SPEAKER_OUT2: u8 = 1 << 5;
+
+const HPET_MMIO_SIZE: usize = 0x400;
+const HPET_CAPABILITIES: usize = 0x00;
+const HPET_CONFIGURATION: usize = 0x10;
+const HPET_MAIN_COUNTER: usize = 0xf0;
+const HPET_ENABLE: u64 = 1 << 0;
+// 立っていなければ、メインカウンタは32ビット
+const HPET_COUNT_SIZE_CAP: u64 = 1 << 13;
+// 仕様では、メインカウンタの周期は0より大きく100ns以下
+const HPET_MAX_PERIOD_FS: u64 = 100_000_000;
+const FEMTOS_PER_SEC: u128 = 1_000_000_000_000_000;
+// HPET表の本体で、汎用アドレス構造体のアドレスが置かれている位置
+const HPET_TABLE_ADDRESS_OFFSET: usize = 8;
+
+const LAPIC_TIMER_INITIAL_COUNT: usize = 0x380;
+const LAPIC_TIMER_CURRENT_COUNT: usize = 0x390;
+const LAPIC_TIMER_DIVIDE: usize = 0x3e0;
+const LAPIC_TIMER_DIVIDE_BY_16: u32 = 0b0011;
+const LVT_TIMER_PERIODIC: u32 = 1 << 17;
+
+static TSC_FREQUENCY_HZ: AtomicU64 = AtomicU64::new(0);
+static TSC_AT_BOOT: AtomicU64 = AtomicU64::new(0);
+static APIC_TIMER_FREQUENCY_HZ: AtomicU64 = AtomicU64::new(0);
+static TICKS: AtomicU64 = AtomicU64::new(0);
+// 周期的な割り込みが止まっているときにhltで待つと、起こされなくなる
+static IS_PERIODIC: AtomicBool = AtomicBool::new(false);
+
+// タイマ割り込みのたびに、それまでの割り込み回数を渡して呼ばれる
+pub type TickCallback = fn(u64);
+
+static TICK_CALLBACKS: Mutex<[Option<TickCallback>; MAX_TICK_CALLBACKS]> =
+    Mutex::new([None; MAX_TICK_CALLBACKS]);
+
+fn ticks_to_duration(ticks: u64, frequency_hz: u64) -> Duration {
+    let nanos = ticks as u128 * NANOS_PER_SEC / frequency_hz as u128;
+    Duration::from_nanos(nanos as u64)
+}
+
+fn duration_to_ticks(duration: Duration, frequency_hz: u64) -> u64 {
+    (duration.as_nanos() * frequency_hz as u128 / NANOS_PER_SEC) as u64
+}
+
+// PITのチャンネル2を使い、msミリ秒待つ間に進んだTSCの値を返す
+fn measure_tsc_with_pit(ms: u64) -> u64 {
+    let count = PIT_FREQUENCY_HZ * ms / 1000;
+    let control = read_io_port_u8(PORT_SPEAKER_CONTROL);
+    write_io_port_u8(
+        PORT_SPEAKER_CONTROL,
+        (control & !SPEAKER_DATA) | SPEAKER_GATE2,
+    );
+    write_io_port_u8(PIT_COMMAND, PIT_CHANNEL2_ONE_SHOT);
+    write_io_port_u8(PIT_CHANNEL2_DATA, count as u8);
+    write_io_port_u8(PIT_CHANNEL2_DATA, (count >> 8) as u8);
+    let start = rdtsc();
+    while read_io_port_u8(PORT_SPEAKER_CONTROL) & SPEAKER_OUT2 == 0 {
+        spin_loop();
+    }
+    let elapsed = rdtsc() - start;
+    write_io_port_u8(PORT_SPEAKER_CONTROL, control);
+    elapsed
+}
+
+// 能力レジスタから、メインカウンタの周期 (フェムト秒) と、カウンタの有効なビットのマスクを得る。
+// 周期が仕様の範囲外なら、そのHPETは使わない
+fn hpet_counter_spec(capabilities: u64) -> Option<(u64, u64)> {
+    let period_fs = capabilities >> 32;
+    if period_fs == 0 || period_fs > HPET_MAX_PERIOD_FS {
+        return None;
+    }
+    let mask = if capabilities & HPET_COUNT_SIZE_CAP != 0 {
+        u64::MAX
+    } else {
+        u32::MAX as u64
+    };
+    Some((period_fs, mask))
+}
+
+// HPETのメインカウンタを使い、msミリ秒待つ間に進んだTSCの値を返す。
+// HPETが使えなければNoneを返すので、呼び出し側はPITで測り直す
+fn measure_tsc_with_hpet(base: usize, ms: u64) -> Option<u64> {
+    let read = |reg: usize| unsafe { read_volatile((base + reg) as *const u64) };
+    let (period_fs, mask) = hpet_counter_spec(read(HPET_CAPABILITIES))?;
+    let count = (ms as u128 * FEMTOS_PER_SEC / 1000 / period_fs as u128) as u64;
+    // 待つ間にカウンタが1周を超えると、経過を測れない
+    if count > mask {
+        return None;
+    }
+    unsafe {
+        write_volatile(
+            (base + HPET_CONFIGURATION) as *mut u64,
+            read(HPET_CONFIGURATION) | HPET_ENABLE,
+        );
+    }
+    // 32ビットのカウンタは、上位ビットを落としてから差を取れば1周の折り返しを扱える
+    let counter = || read(HPET_MAIN_COUNTER) & mask;
+    let counter_start = counter();
+    let start = rdtsc();
+    while counter().wrapping_sub(counter_start) & mask < count {
+        spin_loop();
+    }
+    Some(rdtsc() - start)
+}
+
+fn hpet_base() -> Option<usize> {
+    let table = acpi::find_table(b"HPET")?;
+    let body = table.body();
+    let addr = body.get(HPET_TABLE_ADDRESS_OFFSET..HPET_TABLE_ADDRESS_OFFSET + 8)?;
+    Some(u64::from_le_bytes(addr.try_into().unwrap()) as usize)
+}
+
+// HPETがあればHPETで、なければPITでTSCの周波数を測る
+fn calibrate_tsc() -> u64 {
+    let hpet_base = hpet_base()
+        .filter(|base| paging::map_mmio(*base, HPET_MMIO_SIZE, CacheMode::Uncached).is_ok());
+    let elapsed = hpet_base
+        .and_then(|base| measure_tsc_with_hpet(base, CALIBRATION_MS))
+        .unwrap_or_else(|| measure_tsc_with_pit(CALIBRATION_MS));
+    elapsed * 1000 / CALIBRATION_MS
+}
+
+// TSCを基準に、16分周したローカルAPICタイマの周波数を測る
+fn calibrate_apic_timer() -> u64 {
+    local_apic_write(LAPIC_LVT_TIMER, LVT_MASKED | TIMER_VECTOR as u32);
+    local_apic_write(LAPIC_TIMER_DIVIDE, LAPIC_TIMER_DIVIDE_BY_16);
+    local_apic_write(LAPIC_TIMER_INITIAL_COUNT, u32::MAX);
+    busy_wait(Duration::from_millis(CALIBRATION_MS));
+    let elapsed = u32::MAX - local_apic_read(LAPIC_TIMER_CURRENT_COUNT);
+    local_apic_write(LAPIC_TIMER_INITIAL_COUNT, 0);
+    elapsed as u64 * 1000 / CALIBRATION_MS
+}
+
+// TSCとローカルAPICタイマを校正し、TICK_HZの周期でタイマ割り込みを起こす
+pub fn init() -> Result<()> {
+    TSC_AT_BOOT.store(rdtsc(), Ordering::Relaxed);
+    TSC_FREQUENCY_HZ.store(calibrate_tsc(), Ordering::Relaxed);
+    APIC_TIMER_FREQUENCY_HZ.store(calibrate_apic_timer(), Ordering::Relaxed);
+    idt::set_handler(TIMER_VECTOR, handle_timer_interrupt)?;
+    start_periodic(TICK_HZ)
+}
+
+pub fn tsc_frequency_hz() -> u64 {
+    TSC_FREQUENCY_HZ.load(Ordering::Relaxed)
+}
+
+// 起動してからの経過時間。TSCから求めるので、割り込みが禁止されていても進む
+pub fn now() -> Duration {
+    let elapsed = rdtsc() - TSC_AT_BOOT.load(Ordering::Relaxed);
+    ticks_to_duration(elapsed, tsc_frequency_hz())
+}
+
+fn busy_wait(duration: Duration) {
+    let deadline = rdtsc() + duration_to_ticks(duration, tsc_frequency_hz());
+    while rdtsc() < deadline {
+        spin_loop();
+    }
+}
+
+// 周期的なタイマ割り込みが届くなら、次の割り込みまでhltで休みながら待つ
+pub fn sleep(duration: Duration) {
+    if !are_interrupts_enabled() || !IS_PERIODIC.load(Ordering::SeqCst) {
+        return busy_wait(duration);
+    }
+    let deadline = now() + duration;
+    while now() < deadline {
+        hlt();
+    }
+}
+
+// これまでに起きたタイマ割り込みの回数
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::SeqCst)
+}
+
+pub fn start_periodic(hz: u32) -> Result<()> {
+    if hz == 0 {
+        return Err(Error::OutOfRange);
+    }
+    let count = APIC_TIMER_FREQUENCY_HZ.load(Ordering::Relaxed) / hz as u64;
+    if count == 0 || count > u32::MAX as u64 {
+        return Err(Error::OutOfRange);
+    }
+    local_apic_write(LAPIC_TIMER_DIVIDE, LAPIC_TIMER_DIVIDE_BY_16);
+    local_apic_write(LAPIC_LVT_TIMER, LVT_TIMER_PERIODIC | TIMER_VECTOR as u32);
+    local_apic_write(LAPIC_TIMER_INITIAL_COUNT, count as u32);
+    IS_PERIODIC.store(true, Ordering::SeqCst);
+    Ok(())
+}
+
+// delayだけ経ってから、タイマ割り込みを1回だけ起こす
+pub fn start_one_shot(delay: Duration) -> Result<()> {
+    let count = duration_to_ticks(delay, APIC_TIMER_FREQUENCY_HZ.load(Ordering::Relaxed));
+    if count == 0 || count > u32::MAX as u64 {
+        return Err(Error::OutOfRange);
+    }
+    IS_PERIODIC.store(false, Ordering::SeqCst);
+    local_apic_write(LAPIC_TIMER_DIVIDE, LAPIC_TIMER_DIVIDE_BY_16);
+    local_apic_write(LAPIC_LVT_TIMER, TIMER_VECTOR as u32);
+    local_apic_write(LAPIC_TIMER_INITIAL_COUNT, count as u32);
+    Ok(())
+}
+
+pub fn stop() {
+    IS_PERIODIC.store(false, Ordering::SeqCst);
+    local_apic_write(LAPIC_LVT_TIMER, LVT_MASKED | TIMER_VECTOR as u32);
+    local_apic_write(LAPIC_TIMER_INITIAL_COUNT, 0);
+}
+
+pub fn add_tick_callback(callback: TickCallback) -> Result<()> {
+    let mut callbacks = TICK_CALLBACKS.lock();
+    let slot = callbacks
+        .iter_mut()
+        .find(|e| e.is_none())
+        .ok_or(Error::OutOfRange)?;
+    *slot = Some(callback);
+    Ok(())
+}
+
+pub fn remove_tick_callback(callback: TickCallback) -> Result<()> {
+    let mut callbacks = TICK_CALLBACKS.lock();
+    let slot = callbacks
+        .iter_mut()
+        .find(|e| **e == Some(callback))
+        .ok_or(Error::InvalidParameter)?;
+    *slot = None;
+    Ok(())
+}
+
+fn handle_timer_interrupt(_frame: &mut InterruptFrame) {
+    let ticks = TICKS.fetch_add(1, Ordering::SeqCst) + 1;
+    // コールバックの中から登録や解除ができるよう、写してから呼ぶ
+    let callbacks = *TICK_CALLBACKS.lock();
+    for callback in callbacks.iter().flatten() {
+        callback(ticks);
+    }
+    end_of_interrupt();
+}
+
+#[cfg(test)]
+mod test {
+    use super::add_tick_callback;
+    use super::duration_to_ticks;
+    use super::hpet_counter_spec;
+    use super::now;
+    use super::remove_tick_callback;
+    use super::sleep;
+    use super::start_one_shot;
+    use super::start_periodic;
+    use super::stop;
+    use super::ticks;
+    use super::ticks_to_duration;
+    use super::tsc_frequency_hz;
+    use super::TICK_HZ;
+    use core::sync::atomic::AtomicU64;
+    use core::sync::atomic::Ordering;
+    use core::time::Duration;
+
+    #[test_case]
+    fn tick_conversion_round_trips() {
+        let frequency = 2_500_000_000;
+        assert_eq!(ticks_to_duration(frequency, frequency), Duration::from_secs(1));
+        assert_eq!(duration_to_ticks(Duration::from_millis(2), frequency), 5_000_000);
+    }
+
+    #[test_case]
+    fn hpet_capabilities_are_checked() {
+        // QEMUのHPETは、周期10nsの64ビットカウンタ
+        let period = 10_000_000u64 << 32;
+        assert_eq!(hpet_counter_spec(period | 1 << 13), Some((10_000_000, u64::MAX)));
+        assert_eq!(hpet_counter_spec(period), Some((10_000_000, u32::MAX as u64)));
+        assert_eq!(hpet_counter_spec(1 << 13), None);
+        assert_eq!(hpet_counter_spec(100_000_001u64 << 32 | 1 << 13), None);
+    }
+
+    #[test_case]
+    fn tsc_frequency_is_plausible() {
+        let frequency = tsc_frequency_hz();
+        assert!((100_000_000..10_000_000_000).contains(&frequency));
+    }
+
+    #[test_case]
+    fn sleep_waits_at_least_duration() {
+        let start = now();
+        let start_ticks = ticks();
+        sleep(Duration::from_millis(50));
+        assert!(now() - start >= Duration::from_millis(50));
+        assert!(now() >= start);
+        // 100Hzなら5回ほど割り込みが起きているはず
+        assert!(ticks() - start_ticks >= 2);
+    }
+
+    static CALLBACK_TICKS: AtomicU64 = AtomicU64::new(0);
+
+    fn record_tick(ticks: u64) {
+        CALLBACK_TICKS.store(ticks, Ordering::SeqCst);
+    }
+
+    #[test_case]
+    fn tick_callback_is_called() {
+        CALLBACK_TICKS.store(0, Ordering::SeqCst);
+        add_tick_callback(record_tick).unwrap();
+        sleep(Duration::from_millis(30));
+        remove_tick_callback(record_tick).unwrap();
+        assert_ne!(CALLBACK_TICKS.load(Ordering::SeqCst), 0);
+        assert!(remove_tick_callback(record_tick).is_err());
+    }
+
+    #[test_case]
+    fn one_shot_fires_once() {
+        stop();
+        let start_ticks = ticks();
+        start_one_shot(Duration::from_millis(5)).unwrap();
+        sleep(Duration::from_millis(30));
+        assert_eq!(ticks(), start_ticks + 1);
+        start_periodic(TICK_HZ).unwrap();
+    }
+}